@@ -1,6 +1,7 @@
 /* Fake larger flash so debug builds succeed.
    We can't actually fit these on the stm32, but they
-   allow cargo bloat and clippy to run. */
+   allow cargo bloat and clippy to run. The STORAGE region of
+   memory-release.x is not kept out of it. */
 
 MEMORY
 {
@@ -1,5 +1,7 @@
 MEMORY
 {
-  FLASH : ORIGIN = 0x08004000, LENGTH = 48K
+  FLASH : ORIGIN = 0x08004000, LENGTH = 44K
+  /* Kept out of the firmware image for eeprom::FLASH_STORAGE */
+  STORAGE : ORIGIN = 0x0800F000, LENGTH = 4K
   RAM : ORIGIN = 0x20000000, LENGTH = 16K
 }
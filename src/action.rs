@@ -1,7 +1,11 @@
 use bit_field::BitField;
 
 use crate::bluetooth::BluetoothMode;
-use crate::keycodes::KeyCode;
+use crate::keycodes::{KeyCode, SystemKey};
+use crate::layout::LAYER_COUNT;
+
+/// Bluetooth hosts are saved in slots numbered from 1
+const HOST_COUNT: u8 = 4;
 
 #[allow(dead_code)]
 #[derive(Copy, Clone, PartialEq)]
@@ -14,13 +18,60 @@ pub enum Action {
     Transparent,
     /// Toggle sending HID report over USB
     UsbToggle,
+    /// Toggle between the boot (6KRO) and the N-key rollover USB
+    /// report
+    NkroToggle,
+    /// Captures the next keys typed and runs the action of the
+    /// sequence they match, see `layout::LEADER_SEQUENCES`
+    Leader,
 
     Key(KeyCode), // = 0x10
+    /// Acts as the modifier when held past the tapping term, and
+    /// sends the second key when tapped
+    ModTap(KeyCode, KeyCode),
+    /// Modifier that sticks to the next key press when tapped, and
+    /// locks when tapped twice. Acts as a plain modifier when held.
+    /// Other keys than modifiers act as plain keys.
+    OneShotMod(KeyCode),
+    /// Sends the key together with the modifiers in the mask, see
+    /// `keycodes::MOD_LCTRL` etc. With `KeyCode::No` only the
+    /// modifiers are sent, as for Hyper and Meh.
+    KeyWithMods(u8, KeyCode),
+    /// Plays back `layout::MACROS[index]`, sending a report for each
+    /// step
+    Macro(u8),
+    /// Start recording the keys typed into RAM
+    DynMacroRecord,
+    DynMacroStop,
+    /// Plays back the keys recorded after `DynMacroRecord`
+    DynMacroPlay,
+    /// Shift letters until a key that ends the word is pressed
+    CapsWord,
+    /// Switch auto-shift on or off, see `layout::AUTO_SHIFT`
+    AutoShiftToggle,
+    /// Sends a usage of the consumer page, such as
+    /// `keycodes::PLAY_PAUSE`. Only over USB, as the Bluetooth chip
+    /// takes keyboard reports only.
+    Consumer(u16),
+    /// Sends a system control key over USB
+    System(SystemKey),
 
     LayerMomentary(u8), // = 0x20,
     LayerToggle(u8),
     LayerOn(u8),
     LayerOff(u8),
+    /// Makes the layer the base layer, which stays active and which
+    /// `Transparent` keys fall through to. Kept across resets.
+    SetDefaultLayer(u8),
+    /// Acts as `LayerMomentary` when held past the tapping term, and
+    /// sends the key when tapped
+    LayerTap(u8, KeyCode),
+    /// Activates the layer for the next key press when tapped, and
+    /// acts as `LayerMomentary` when held
+    OneShotLayer(u8),
+    /// Runs one of the actions in `layout::TAP_DANCES[index]`
+    /// depending on how often the key is tapped
+    TapDance(u8),
 
     LedOn, // = 0x30,
     LedOff,
@@ -40,6 +91,18 @@ pub enum Action {
     BtLegacyMode(bool),
     BtToggleLegacyMode,
     BtHostListQuery, // TODO: remove? this shouldn't really be here
+
+    /// Moves the mouse cursor while held, faster the longer it is
+    /// held, see `layout::MOUSE_KEYS`
+    MouseUp, // = 0x50,
+    MouseDown,
+    MouseLeft,
+    MouseRight,
+    MouseWheelUp,
+    MouseWheelDown,
+    /// Presses the mouse buttons in the mask: 1 left, 2 right and 4
+    /// middle
+    MouseButton(u8),
 }
 
 // Allow auto-conversion of KeyCodes to Action for nicer layout formatting
@@ -64,12 +127,183 @@ impl Action {
     pub const fn to_action(self) -> Action {
         self
     }
+
+    /// Whether this action only modifies other keys
+    pub fn is_modifier(self) -> bool {
+        match self {
+            Action::Key(code) | Action::OneShotMod(code) => code.is_modifier(),
+            Action::KeyWithMods(_, code) => code == KeyCode::No,
+            Action::OneShotLayer(_) => true,
+            _ => false,
+        }
+    }
+
+    /// Whether this action must wait for a tap-hold decision when
+    /// pressed, see [`tapping::Tapping`]
+    pub fn is_tap_hold(self) -> bool {
+        match self {
+            Action::ModTap(_, _) | Action::LayerTap(_, _) | Action::TapDance(_) => true,
+            _ => false,
+        }
+    }
+
+    /// Pack the action into a word, with the opcode in the top byte
+    /// and the arguments below it. Opcodes are grouped as in the
+    /// comments on `Action`.
+    pub fn to_word(self) -> u32 {
+        use Action::*;
+        let (opcode, args): (u32, u32) = match self {
+            Nop => (0x00, 0),
+            Reset => (0x01, 0),
+            Transparent => (0x02, 0),
+            UsbToggle => (0x03, 0),
+            NkroToggle => (0x04, 0),
+            Leader => (0x05, 0),
+
+            Key(code) => (0x10, code as u32),
+            ModTap(hold, tap) => (0x11, (hold as u32) << 8 | tap as u32),
+            OneShotMod(code) => (0x12, code as u32),
+            KeyWithMods(mods, code) => (0x13, u32::from(mods) << 8 | code as u32),
+            Macro(index) => (0x14, u32::from(index)),
+            DynMacroRecord => (0x15, 0),
+            DynMacroStop => (0x16, 0),
+            DynMacroPlay => (0x17, 0),
+            CapsWord => (0x18, 0),
+            AutoShiftToggle => (0x19, 0),
+            Consumer(usage) => (0x1a, u32::from(usage)),
+            System(key) => (0x1b, key as u32),
+
+            LayerMomentary(layer) => (0x20, u32::from(layer)),
+            LayerToggle(layer) => (0x21, u32::from(layer)),
+            LayerOn(layer) => (0x22, u32::from(layer)),
+            LayerOff(layer) => (0x23, u32::from(layer)),
+            SetDefaultLayer(layer) => (0x24, u32::from(layer)),
+            LayerTap(layer, code) => (0x25, u32::from(layer) << 8 | code as u32),
+            OneShotLayer(layer) => (0x26, u32::from(layer)),
+            TapDance(index) => (0x27, u32::from(index)),
+
+            LedOn => (0x30, 0),
+            LedOff => (0x31, 0),
+            LedToggle => (0x32, 0),
+            LedNextTheme => (0x33, 0),
+            LedNextBrightness => (0x34, 0),
+            LedNextAnimationSpeed => (0x35, 0),
+            LedTheme(theme) => (0x36, u32::from(theme)),
+
+            BtOn => (0x40, 0),
+            BtOff => (0x41, 0),
+            BtSaveHost(host) => (0x42, u32::from(host)),
+            BtConnectHost(host) => (0x43, u32::from(host)),
+            BtDeleteHost(host) => (0x44, u32::from(host)),
+            BtBroadcast => (0x45, 0),
+            BtLegacyMode(enabled) => (0x46, u32::from(enabled)),
+            BtToggleLegacyMode => (0x47, 0),
+            BtHostListQuery => (0x48, 0),
+
+            MouseUp => (0x50, 0),
+            MouseDown => (0x51, 0),
+            MouseLeft => (0x52, 0),
+            MouseRight => (0x53, 0),
+            MouseWheelUp => (0x54, 0),
+            MouseWheelDown => (0x55, 0),
+            MouseButton(mask) => (0x56, u32::from(mask)),
+        };
+        opcode << 24 | args
+    }
+
+    /// The action packed into `word` by `to_word`, if it is one
+    pub fn from_word(word: u32) -> Option<Action> {
+        use Action::*;
+        let byte = word as u8;
+        let code = KeyCode::from_u8(byte);
+        let high_code = KeyCode::from_u8((word >> 8) as u8);
+        let high_byte = (word >> 8) as u8;
+        let action = match word >> 24 {
+            0x00 => Nop,
+            0x01 => Reset,
+            0x02 => Transparent,
+            0x03 => UsbToggle,
+            0x04 => NkroToggle,
+            0x05 => Leader,
+
+            0x10 => Key(code?),
+            0x11 => ModTap(high_code?, code?),
+            0x12 => OneShotMod(code.filter(|code| code.is_modifier())?),
+            0x13 => KeyWithMods(high_byte, code?),
+            0x14 => Macro(byte),
+            0x15 => DynMacroRecord,
+            0x16 => DynMacroStop,
+            0x17 => DynMacroPlay,
+            0x18 => CapsWord,
+            0x19 => AutoShiftToggle,
+            0x1a => Consumer(word as u16),
+            0x1b => System(SystemKey::from_u8(byte)?),
+
+            0x20 => LayerMomentary(byte),
+            0x21 => LayerToggle(byte),
+            0x22 => LayerOn(byte),
+            0x23 => LayerOff(byte),
+            0x24 => SetDefaultLayer(byte),
+            0x25 => LayerTap(high_byte, code?),
+            0x26 => OneShotLayer(byte),
+            0x27 => TapDance(byte),
+
+            0x30 => LedOn,
+            0x31 => LedOff,
+            0x32 => LedToggle,
+            0x33 => LedNextTheme,
+            0x34 => LedNextBrightness,
+            0x35 => LedNextAnimationSpeed,
+            0x36 => LedTheme(byte),
+
+            0x40 => BtOn,
+            0x41 => BtOff,
+            0x42 => BtSaveHost(byte),
+            0x43 => BtConnectHost(byte),
+            0x44 => BtDeleteHost(byte),
+            0x45 => BtBroadcast,
+            0x46 => BtLegacyMode(byte != 0),
+            0x47 => BtToggleLegacyMode,
+            0x48 => BtHostListQuery,
+
+            0x50 => MouseUp,
+            0x51 => MouseDown,
+            0x52 => MouseLeft,
+            0x53 => MouseRight,
+            0x54 => MouseWheelUp,
+            0x55 => MouseWheelDown,
+            0x56 => MouseButton(byte),
+            _ => return None,
+        };
+        Some(action).filter(|action| action.is_valid())
+    }
+
+    /// Whether the layers and Bluetooth hosts the action refers to
+    /// exist
+    pub fn is_valid(self) -> bool {
+        use Action::*;
+        match self {
+            LayerMomentary(layer)
+            | LayerToggle(layer)
+            | LayerOn(layer)
+            | LayerOff(layer)
+            | SetDefaultLayer(layer)
+            | LayerTap(layer, _)
+            | OneShotLayer(layer) => (layer as usize) < LAYER_COUNT,
+            BtSaveHost(host) | BtConnectHost(host) | BtDeleteHost(host) => {
+                (1..=HOST_COUNT).contains(&host)
+            }
+            _ => true,
+        }
+    }
+
     pub fn to_color(
         &self,
         saved_hosts: u8,
         connected_host: u8,
         mode: BluetoothMode,
         keyboard_send_usb_report: bool,
+        caps_word: bool,
     ) -> Option<(u8, u8, u8, u8)> {
         use self::Action::*;
         use crate::layout::LAYER_FN;
@@ -88,12 +322,17 @@ impl Action {
         match *self {
             UsbToggle if keyboard_send_usb_report => WHITE,
             UsbToggle => Some((0xff, 0xff, 0xff, FLASH)),
-            BtHostListQuery | LedNextBrightness | LayerMomentary(LAYER_FN) => WHITE,
+            CapsWord if caps_word => Some((0xff, 0xff, 0xff, FLASH)),
+            CapsWord => WHITE,
+            BtHostListQuery
+            | LedNextBrightness
+            | LayerMomentary(LAYER_FN)
+            | LayerTap(LAYER_FN, _) => WHITE,
             Reset | LedOff | BtOff | Key(KeyCode::LMeta) | Key(KeyCode::RMeta)
             | Key(KeyCode::V) => RED,
             BtBroadcast | LedNextAnimationSpeed => GREEN,
             BtOn | Key(KeyCode::N6) | Key(KeyCode::N9) | Key(KeyCode::C) => BLUE,
-            LayerToggle(_) => YELLOW,
+            LayerToggle(_) | OneShotLayer(_) => YELLOW,
             LayerOff(_) | LedNextTheme | LedOn => Some((0, 0xff, 0, FLASH)),
 
             Key(KeyCode::N1) if connected_host == 1 => CYAN,
@@ -113,7 +352,8 @@ impl Action {
                 BluetoothMode::Legacy => YELLOW,
             },
 
-            Key(code) if code.is_modifier() => GREEN,
+            Key(code) | ModTap(code, _) | OneShotMod(code) if code.is_modifier() => GREEN,
+            KeyWithMods(_, KeyCode::No) => GREEN,
             Key(code) if KeyCode::PScreen <= code && code <= KeyCode::Up => WHITE,
 
             _ => None,
@@ -1,5 +1,12 @@
 use stm32l1::stm32l151;
 
+/// Core clock set up by `init_clock`: 16 MHz HSE, PLL x6 / 3
+pub const SYSCLK_HZ: u32 = 32_000_000;
+
+/// Period of the SysTick interrupt, which paces the matrix scan and
+/// all key timing
+pub const TICK_MS: u32 = 3;
+
 pub fn init_clock(p: &stm32l151::Peripherals) {
     p.USB.usb_cntr.modify(|_, w| w.pdwn().clear_bit());
 
@@ -0,0 +1,462 @@
+use crate::action::Action;
+use crate::crc::Crc32;
+use crate::keymatrix::{COLUMNS, ROWS};
+use crate::layout::Layout;
+use crate::led::LedConfig;
+
+/// Starts every blob
+const MAGIC: [u8; 2] = *b"AP";
+/// Bumped whenever the encoding changes, so that blobs of other
+/// firmware are rejected rather than misread
+pub const FORMAT_VERSION: u8 = 1;
+
+/// Magic, version, kind, payload length and one byte that depends on
+/// the kind
+const HEADER_SIZE: usize = 7;
+const CRC_SIZE: usize = 4;
+/// Longest encoding of an action: the opcode and two arguments
+const MAX_ACTION_SIZE: usize = 3;
+
+/// Longest blob of a layout
+pub const LAYOUT_BLOB_SIZE: usize = HEADER_SIZE + COLUMNS * ROWS * MAX_ACTION_SIZE + CRC_SIZE;
+/// Size of a blob of lighting settings
+#[allow(dead_code)]
+pub const LIGHTING_BLOB_SIZE: usize = HEADER_SIZE + 4 + CRC_SIZE;
+
+#[derive(Copy, Clone, PartialEq)]
+enum Kind {
+    /// A `Layout`, along with its layer number
+    Layout = 1,
+    /// Lighting on/off and `LedConfig`
+    Lighting = 2,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum CodecError {
+    /// Not a blob, or one of another kind
+    Magic,
+    /// A blob of another format version
+    Version,
+    /// The blob is cut short, or the payload is of the wrong length
+    Length,
+    Crc,
+    /// An action with an unknown opcode or invalid arguments
+    Opcode,
+    /// The buffer can not hold the blob
+    BufferTooSmall,
+}
+
+impl Action {
+    /// Number of argument bytes following the opcode, see `to_word`
+    fn argument_count(self) -> usize {
+        use Action::*;
+        match self {
+            Nop | Reset | Transparent | UsbToggle | NkroToggle | Leader => 0,
+            DynMacroRecord | DynMacroStop | DynMacroPlay | CapsWord | AutoShiftToggle => 0,
+            LedOn | LedOff | LedToggle | LedNextTheme | LedNextBrightness => 0,
+            LedNextAnimationSpeed => 0,
+            BtOn | BtOff | BtBroadcast | BtToggleLegacyMode | BtHostListQuery => 0,
+            MouseUp | MouseDown | MouseLeft | MouseRight | MouseWheelUp | MouseWheelDown => 0,
+
+            Key(_) | OneShotMod(_) | Macro(_) | System(_) => 1,
+            LayerMomentary(_) | LayerToggle(_) | LayerOn(_) | LayerOff(_) => 1,
+            SetDefaultLayer(_) | OneShotLayer(_) | TapDance(_) | LedTheme(_) => 1,
+            BtSaveHost(_) | BtConnectHost(_) | BtDeleteHost(_) | BtLegacyMode(_) => 1,
+            MouseButton(_) => 1,
+
+            ModTap(_, _) | KeyWithMods(_, _) | Consumer(_) | LayerTap(_, _) => 2,
+        }
+    }
+
+    /// Encode the action into `buffer`: its opcode, then its
+    /// arguments with the most significant byte first. Returns the
+    /// number of bytes written.
+    pub fn encode(self, buffer: &mut [u8]) -> Result<usize, CodecError> {
+        let word = self.to_word();
+        let count = self.argument_count();
+        let bytes = buffer.get_mut(..=count).ok_or(CodecError::BufferTooSmall)?;
+        bytes[0] = (word >> 24) as u8;
+        for (i, byte) in bytes[1..].iter_mut().enumerate() {
+            *byte = (word >> (8 * (count - 1 - i))) as u8;
+        }
+        Ok(1 + count)
+    }
+
+    /// Decode the action at the start of `bytes`, along with the
+    /// number of bytes it takes up.
+    pub fn decode(bytes: &[u8]) -> Result<(Action, usize), CodecError> {
+        let opcode = *bytes.get(0).ok_or(CodecError::Length)?;
+        // the action of the opcode that takes as many arguments as
+        // were read, reading zeros past the end of `bytes`
+        for count in 0..MAX_ACTION_SIZE {
+            let arguments = (1..=count).fold(0, |arguments, i| {
+                arguments << 8 | u32::from(bytes.get(i).copied().unwrap_or(0))
+            });
+            let word = u32::from(opcode) << 24 | arguments;
+            match Action::from_word(word) {
+                // arguments out of range decode to a different action
+                Some(action) if action.argument_count() == count && action.to_word() == word => {
+                    if bytes.len() <= count {
+                        return Err(CodecError::Length);
+                    }
+                    return Ok((action, 1 + count));
+                }
+                _ => {}
+            }
+        }
+        Err(CodecError::Opcode)
+    }
+}
+
+/// Encode `layout` as layer `layer` into `buffer`, returning the size
+/// of the blob.
+pub fn encode_layout(layer: u8, layout: &Layout, buffer: &mut [u8]) -> Result<usize, CodecError> {
+    let mut length = 0;
+    {
+        let payload = buffer
+            .get_mut(HEADER_SIZE..)
+            .ok_or(CodecError::BufferTooSmall)?;
+        for action in layout.iter() {
+            let rest = payload
+                .get_mut(length..)
+                .ok_or(CodecError::BufferTooSmall)?;
+            length += action.encode(rest)?;
+        }
+    }
+    seal(buffer, Kind::Layout, layer, length)
+}
+
+/// Decode a blob made by `encode_layout`, returning the layer number
+/// and the layout.
+pub fn decode_layout(blob: &[u8]) -> Result<(u8, Layout), CodecError> {
+    let (layer, payload) = open(blob, Kind::Layout)?;
+    let mut layout = [Action::Nop; COLUMNS * ROWS];
+    let mut position = 0;
+    for action in layout.iter_mut() {
+        let (decoded, size) = Action::decode(&payload[position..])?;
+        *action = decoded;
+        position += size;
+    }
+    if position != payload.len() {
+        return Err(CodecError::Length);
+    }
+    Ok((layer, layout))
+}
+
+/// Encode the lighting settings into `buffer`, returning the size of
+/// the blob.
+#[allow(dead_code)]
+pub fn encode_lighting(
+    on: bool,
+    config: &LedConfig,
+    buffer: &mut [u8],
+) -> Result<usize, CodecError> {
+    let payload = [
+        on as u8,
+        config.theme,
+        config.brightness,
+        config.animation_speed,
+    ];
+    buffer
+        .get_mut(HEADER_SIZE..HEADER_SIZE + payload.len())
+        .ok_or(CodecError::BufferTooSmall)?
+        .copy_from_slice(&payload);
+    seal(buffer, Kind::Lighting, 0, payload.len())
+}
+
+/// Decode a blob made by `encode_lighting`.
+#[allow(dead_code)]
+pub fn decode_lighting(blob: &[u8]) -> Result<(bool, LedConfig), CodecError> {
+    match open(blob, Kind::Lighting)? {
+        (_, &[on, theme, brightness, animation_speed]) if on <= 1 => Ok((
+            on == 1,
+            LedConfig {
+                theme,
+                brightness,
+                animation_speed,
+            },
+        )),
+        _ => Err(CodecError::Length),
+    }
+}
+
+/// Fill in the header and CRC around the `length` bytes of payload
+/// in `buffer`.
+fn seal(buffer: &mut [u8], kind: Kind, extra: u8, length: usize) -> Result<usize, CodecError> {
+    let size = HEADER_SIZE + length + CRC_SIZE;
+    if buffer.len() < size {
+        return Err(CodecError::BufferTooSmall);
+    }
+    let [length_low, length_high] = (length as u16).to_le_bytes();
+    buffer[..HEADER_SIZE].copy_from_slice(&[
+        MAGIC[0],
+        MAGIC[1],
+        FORMAT_VERSION,
+        kind as u8,
+        length_low,
+        length_high,
+        extra,
+    ]);
+    let crc = crc(&buffer[..HEADER_SIZE + length]);
+    buffer[HEADER_SIZE + length..size].copy_from_slice(&crc.to_le_bytes());
+    Ok(size)
+}
+
+/// Check the header and CRC of `blob`, returning the byte that
+/// depends on the kind and the payload.
+fn open(blob: &[u8], kind: Kind) -> Result<(u8, &[u8]), CodecError> {
+    let header = blob.get(..HEADER_SIZE).ok_or(CodecError::Length)?;
+    if header[..2] != MAGIC || header[3] != kind as u8 {
+        return Err(CodecError::Magic);
+    }
+    if header[2] != FORMAT_VERSION {
+        return Err(CodecError::Version);
+    }
+    let length = usize::from(u16::from_le_bytes([header[4], header[5]]));
+    let end = HEADER_SIZE + length;
+    let stored = blob.get(end..end + CRC_SIZE).ok_or(CodecError::Length)?;
+    if crc(&blob[..end]).to_le_bytes() != stored {
+        return Err(CodecError::Crc);
+    }
+    Ok((header[6], &blob[HEADER_SIZE..end]))
+}
+
+fn crc(bytes: &[u8]) -> u32 {
+    let mut crc = Crc32::new();
+    crc.update(bytes);
+    crc.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keycodes::{KeyCode, SystemKey, PLAY_PAUSE};
+    use crate::layout::{LAYERS, LAYER_COUNT};
+
+    /// Every variant of `Action`, each with an opcode of its own
+    const ACTIONS: [Action; 56] = [
+        Action::Nop,
+        Action::Reset,
+        Action::Transparent,
+        Action::UsbToggle,
+        Action::NkroToggle,
+        Action::Leader,
+        Action::Key(KeyCode::A),
+        Action::ModTap(KeyCode::LCtrl, KeyCode::Escape),
+        Action::OneShotMod(KeyCode::RShift),
+        Action::KeyWithMods(0x0f, KeyCode::No),
+        Action::Macro(3),
+        Action::DynMacroRecord,
+        Action::DynMacroStop,
+        Action::DynMacroPlay,
+        Action::CapsWord,
+        Action::AutoShiftToggle,
+        Action::Consumer(PLAY_PAUSE),
+        Action::System(SystemKey::WakeUp),
+        Action::LayerMomentary(1),
+        Action::LayerToggle(2),
+        Action::LayerOn(3),
+        Action::LayerOff(4),
+        Action::SetDefaultLayer(5),
+        Action::LayerTap(1, KeyCode::Space),
+        Action::OneShotLayer(2),
+        Action::TapDance(0),
+        Action::LedOn,
+        Action::LedOff,
+        Action::LedToggle,
+        Action::LedNextTheme,
+        Action::LedNextBrightness,
+        Action::LedNextAnimationSpeed,
+        Action::LedTheme(7),
+        Action::BtOn,
+        Action::BtOff,
+        Action::BtSaveHost(1),
+        Action::BtConnectHost(2),
+        Action::BtDeleteHost(3),
+        Action::BtBroadcast,
+        Action::BtLegacyMode(true),
+        Action::BtToggleLegacyMode,
+        Action::BtHostListQuery,
+        Action::MouseUp,
+        Action::MouseDown,
+        Action::MouseLeft,
+        Action::MouseRight,
+        Action::MouseWheelUp,
+        Action::MouseWheelDown,
+        Action::MouseButton(5),
+        // and again with other arguments
+        Action::Key(KeyCode::No),
+        Action::ModTap(KeyCode::RAlt, KeyCode::Z),
+        Action::KeyWithMods(0x02, KeyCode::N1),
+        Action::Consumer(0x1ff),
+        Action::System(SystemKey::PowerDown),
+        Action::LayerTap(5, KeyCode::LShift),
+        Action::BtLegacyMode(false),
+    ];
+
+    fn round_trip(action: Action) {
+        let mut buffer = [0xaa; MAX_ACTION_SIZE + 1];
+        let size = action.encode(&mut buffer).ok().expect("encoded");
+        assert!(size <= MAX_ACTION_SIZE);
+        assert_eq!(buffer[size], 0xaa);
+        match Action::decode(&buffer[..size]) {
+            Ok((decoded, decoded_size)) => {
+                assert!(decoded == action, "opcode {:#x}", buffer[0]);
+                assert_eq!(decoded_size, size);
+            }
+            Err(e) => panic!("opcode {:#x}: {:?}", buffer[0], e),
+        }
+        assert!(Action::decode(&buffer[..size - 1]).is_err());
+    }
+
+    #[test]
+    fn every_action_round_trips() {
+        for &action in ACTIONS.iter() {
+            round_trip(action);
+        }
+    }
+
+    #[test]
+    fn unknown_opcodes_are_rejected() {
+        for opcode in 0..=0xff {
+            let known = ACTIONS
+                .iter()
+                .any(|action| action.to_word() >> 24 == u32::from(opcode));
+            if !known {
+                for &argument in [0x00, 0x04, 0xe0, 0xff].iter() {
+                    let bytes = [opcode, argument, argument];
+                    assert_eq!(Action::decode(&bytes).err(), Some(CodecError::Opcode));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn invalid_arguments_are_rejected() {
+        // not a key code, not a system key, not a modifier
+        for &bytes in [[0x10, 0xff, 0], [0x1b, 0x80, 0], [0x12, 0x04, 0]].iter() {
+            assert_eq!(Action::decode(&bytes).err(), Some(CodecError::Opcode));
+        }
+        assert_eq!(Action::decode(&[]).err(), Some(CodecError::Length));
+    }
+
+    #[test]
+    fn layers_and_hosts_out_of_range_are_rejected() {
+        let last = LAYER_COUNT as u8 - 1;
+        let layer_actions: [fn(u8) -> Action; 7] = [
+            Action::LayerMomentary,
+            Action::LayerToggle,
+            Action::LayerOn,
+            Action::LayerOff,
+            Action::SetDefaultLayer,
+            Action::OneShotLayer,
+            |layer| Action::LayerTap(layer, KeyCode::A),
+        ];
+        let host_actions: [fn(u8) -> Action; 3] = [
+            Action::BtSaveHost,
+            Action::BtConnectHost,
+            Action::BtDeleteHost,
+        ];
+        let mut buffer = [0; MAX_ACTION_SIZE];
+        let mut rejected = |action: Action| {
+            let size = action.encode(&mut buffer).ok().expect("encoded");
+            Action::decode(&buffer[..size]).err() == Some(CodecError::Opcode)
+        };
+        for layer_action in layer_actions.iter() {
+            round_trip(layer_action(last));
+            assert!(rejected(layer_action(last + 1)));
+        }
+        for host_action in host_actions.iter() {
+            round_trip(host_action(1));
+            round_trip(host_action(4));
+            assert!(rejected(host_action(0)));
+            assert!(rejected(host_action(5)));
+        }
+    }
+
+    #[test]
+    fn layouts_round_trip() {
+        let mut blob = [0; LAYOUT_BLOB_SIZE];
+        for (i, layout) in LAYERS.iter().enumerate() {
+            let size = encode_layout(i as u8, layout, &mut blob).unwrap();
+            let (layer, decoded) = decode_layout(&blob[..size]).unwrap();
+            assert_eq!(layer, i as u8);
+            assert!(decoded.iter().zip(layout.iter()).all(|(a, b)| a == b));
+        }
+
+        let mut layout = [Action::Nop; COLUMNS * ROWS];
+        for (action, &other) in layout.iter_mut().zip(ACTIONS.iter().cycle()) {
+            *action = other;
+        }
+        let size = encode_layout(1, &layout, &mut blob).unwrap();
+        let (_, decoded) = decode_layout(&blob[..size]).unwrap();
+        assert!(decoded.iter().zip(layout.iter()).all(|(a, b)| a == b));
+
+        // the longest layout fits
+        let layout = [Action::ModTap(KeyCode::LCtrl, KeyCode::A); COLUMNS * ROWS];
+        assert_eq!(encode_layout(0, &layout, &mut blob), Ok(LAYOUT_BLOB_SIZE));
+        assert_eq!(
+            encode_layout(0, &layout, &mut blob[..LAYOUT_BLOB_SIZE - 1]),
+            Err(CodecError::BufferTooSmall)
+        );
+    }
+
+    #[test]
+    fn lighting_round_trips() {
+        let config = LedConfig {
+            theme: 3,
+            brightness: 7,
+            animation_speed: 2,
+        };
+        let mut blob = [0; LIGHTING_BLOB_SIZE];
+        assert_eq!(
+            encode_lighting(true, &config, &mut blob),
+            Ok(LIGHTING_BLOB_SIZE)
+        );
+        match decode_lighting(&blob) {
+            Ok((on, decoded)) => assert!(on && decoded == config),
+            Err(e) => panic!("{:?}", e),
+        }
+        // a lighting blob is not a layout
+        assert_eq!(decode_layout(&blob).err(), Some(CodecError::Magic));
+    }
+
+    #[test]
+    fn broken_blobs_are_rejected() {
+        let mut blob = [0; LAYOUT_BLOB_SIZE];
+        let size = encode_layout(0, &LAYERS[0], &mut blob).unwrap();
+        let check = |blob: &[u8], error| assert_eq!(decode_layout(blob).err(), Some(error));
+        assert!(decode_layout(&blob[..size]).is_ok());
+        check(&blob[..HEADER_SIZE - 1], CodecError::Length);
+        check(&blob[..size - 1], CodecError::Length);
+
+        let mut broken = blob;
+        broken[0] = b'X';
+        check(&broken, CodecError::Magic);
+
+        let mut broken = blob;
+        broken[2] = FORMAT_VERSION + 1;
+        check(&broken, CodecError::Version);
+
+        let mut broken = blob;
+        broken[HEADER_SIZE] ^= 1;
+        check(&broken, CodecError::Crc);
+
+        let mut broken = blob;
+        broken[size - 1] ^= 1;
+        check(&broken, CodecError::Crc);
+
+        // a payload one action short, sealed with a correct CRC
+        let payload = size - HEADER_SIZE - CRC_SIZE;
+        let mut short = blob;
+        seal(&mut short, Kind::Layout, 0, payload - 1).unwrap();
+        check(&short, CodecError::Length);
+
+        // and one with an extra action
+        let mut long = [0; LAYOUT_BLOB_SIZE + 1];
+        long[..size].copy_from_slice(&blob[..size]);
+        long[HEADER_SIZE + payload] = 0x00;
+        seal(&mut long, Kind::Layout, 0, payload + 1).unwrap();
+        check(&long, CodecError::Length);
+    }
+}
@@ -0,0 +1,332 @@
+use crate::action::Action;
+use crate::event::{elapsed, EventQueue};
+use crate::keymatrix::KeyState;
+use bit_field::BitArray;
+
+#[allow(dead_code)]
+#[derive(Copy, Clone, PartialEq)]
+pub enum ComboTrigger {
+    /// Fire as soon as all keys are down, and release the action with
+    /// the first key of the combo
+    OnPress,
+    /// Tap the action once all keys have been let go
+    OnRelease,
+}
+
+/// Several physical keys pressed together, standing in for `action`
+pub struct Combo {
+    /// Positions in the scan matrix, see [`keycodes::KeyIndex`]
+    pub keys: &'static [u8],
+    pub action: Action,
+    /// Milliseconds between the first and the last key press
+    pub timeout: u32,
+    pub trigger: ComboTrigger,
+}
+
+impl Combo {
+    /// Whether all `count` keys in `pressed` are part of this combo
+    fn covers(&self, pressed: &KeyState, count: usize) -> bool {
+        self.keys
+            .iter()
+            .filter(|&&key| pressed.get_bit(key as usize))
+            .count()
+            == count
+    }
+}
+
+pub enum ComboState {
+    /// The key at the head of the queue is not part of a combo
+    NoMatch,
+    /// Waiting for more keys of the combo
+    Pending,
+    /// The key at the head of the queue now stands for this action
+    Fired(Action),
+}
+
+/// Turns combos of key presses into a single action.
+///
+/// Like tap-hold keys, combos are detected at the head of the event
+/// queue. The first key of a combo is kept as the one carrying the
+/// combo action, while the events of the other keys are dropped.
+pub struct Combos {
+    /// Keys whose release is dropped, as their press went into a
+    /// combo
+    swallowed: KeyState,
+}
+
+impl Combos {
+    pub const fn new() -> Combos {
+        Combos { swallowed: [0; 9] }
+    }
+
+    /// Whether the release of `key` belongs to a fired combo and
+    /// should be dropped
+    pub fn swallow(&mut self, key: usize) -> bool {
+        let swallowed = self.swallowed.get_bit(key);
+        self.swallowed.set_bit(key, false);
+        swallowed
+    }
+
+    /// Check whether the press at the head of `queue` starts one of
+    /// `combos`.
+    pub fn decide(&mut self, queue: &mut EventQueue, combos: &[Combo], now: u32) -> ComboState {
+        let press = match queue.peek() {
+            Some(press) => press,
+            None => return ComboState::NoMatch,
+        };
+        if !combos.iter().any(|c| c.keys.contains(&press.key)) {
+            return ComboState::NoMatch;
+        }
+
+        let mut pressed: KeyState = [0; 9];
+        pressed.set_bit(press.key as usize, true);
+        let mut count = 1;
+        let mut last_press = press.time;
+        // a key was released or a key outside any combo pressed
+        let mut ended = false;
+
+        for event in queue.iter().skip(1) {
+            if event.pressed {
+                let mut next = pressed;
+                next.set_bit(event.key as usize, true);
+                let candidate = combos.iter().any(|c| {
+                    c.covers(&next, count + 1) && elapsed(event.time, press.time) <= c.timeout
+                });
+                if !candidate {
+                    ended = true;
+                    break;
+                }
+                pressed = next;
+                count += 1;
+                last_press = event.time;
+            } else if pressed.get_bit(event.key as usize) {
+                ended = true;
+                break;
+            }
+        }
+
+        let complete = combos.iter().position(|c| {
+            c.keys.len() == count
+                && c.covers(&pressed, count)
+                && elapsed(last_press, press.time) <= c.timeout
+        });
+        // a bigger combo could still be completed
+        let waiting = !ended
+            && !queue.is_full()
+            && combos.iter().any(|c| {
+                c.keys.len() > count
+                    && c.covers(&pressed, count)
+                    && elapsed(now, press.time) <= c.timeout
+            });
+
+        match complete {
+            _ if waiting => ComboState::Pending,
+            None => ComboState::NoMatch,
+            Some(index) => {
+                let combo = &combos[index];
+                if combo.trigger == ComboTrigger::OnRelease && !queue.is_full() {
+                    let all_released = combo
+                        .keys
+                        .iter()
+                        .all(|&key| queue.iter().any(|e| e.key == key && !e.pressed));
+                    if !all_released {
+                        return ComboState::Pending;
+                    }
+                }
+                self.fire(queue, combo);
+                ComboState::Fired(combo.action)
+            }
+        }
+    }
+
+    /// Drop the events of the combo keys, except for the press of the
+    /// first one and its release if it is in the queue already.
+    fn fire(&mut self, queue: &mut EventQueue, combo: &Combo) {
+        let first = match queue.peek() {
+            Some(press) => press.key,
+            None => return,
+        };
+        // keys whose press and release have both been dropped
+        let mut done: KeyState = [0; 9];
+        let mut i = 1;
+        while let Some(event) = queue.get(i) {
+            let key = event.key as usize;
+            if event.key != first && combo.keys.contains(&event.key) && !done.get_bit(key) {
+                queue.remove(i);
+                self.swallowed.set_bit(key, event.pressed);
+                done.set_bit(key, !event.pressed);
+            } else {
+                i += 1;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::event::KeyEvent;
+    use crate::keycodes::KeyCode;
+
+    const ESCAPE: Action = Action::Key(KeyCode::Escape);
+    const TAB: Action = Action::Key(KeyCode::Tab);
+    const COMBOS: [Combo; 2] = [
+        Combo {
+            keys: &[1, 2],
+            action: ESCAPE,
+            timeout: 50,
+            trigger: ComboTrigger::OnPress,
+        },
+        Combo {
+            keys: &[3, 4, 5],
+            action: TAB,
+            timeout: 50,
+            trigger: ComboTrigger::OnRelease,
+        },
+    ];
+
+    /// Queue the presses (`true`) and releases of keys at the given
+    /// milliseconds, as the matrix scan would
+    fn script(timeline: &[(u32, u8, bool)]) -> EventQueue {
+        let mut queue = EventQueue::new();
+        for &(time, key, pressed) in timeline {
+            let event = KeyEvent { key, pressed, time };
+            assert!(queue.push(event).is_ok());
+        }
+        queue
+    }
+
+    fn fired(state: ComboState, action: Action) -> bool {
+        match state {
+            ComboState::Fired(fired) => fired == action,
+            _ => false,
+        }
+    }
+
+    fn pending(state: ComboState) -> bool {
+        match state {
+            ComboState::Pending => true,
+            _ => false,
+        }
+    }
+
+    fn no_match(state: ComboState) -> bool {
+        match state {
+            ComboState::NoMatch => true,
+            _ => false,
+        }
+    }
+
+    /// The keys and states left in `queue`
+    fn events(queue: &EventQueue) -> [Option<(u8, bool)>; 4] {
+        let mut events = [None; 4];
+        for (slot, event) in events.iter_mut().zip(queue.iter()) {
+            *slot = Some((event.key, event.pressed));
+        }
+        events
+    }
+
+    #[test]
+    fn other_keys_do_not_match() {
+        let mut combos = Combos::new();
+        let mut queue = script(&[(0, 9, true)]);
+        assert!(no_match(combos.decide(&mut queue, &COMBOS, 0)));
+        let mut queue = EventQueue::new();
+        assert!(no_match(combos.decide(&mut queue, &COMBOS, 0)));
+    }
+
+    #[test]
+    fn fires_once_all_keys_are_down() {
+        let mut combos = Combos::new();
+        let mut queue = script(&[(0, 1, true), (20, 2, true)]);
+        assert!(fired(combos.decide(&mut queue, &COMBOS, 20), ESCAPE));
+        // the first key carries the action
+        assert_eq!(events(&queue), [Some((1, true)), None, None, None]);
+
+        // and the release of the other one is dropped, once
+        assert!(!combos.swallow(1));
+        assert!(combos.swallow(2));
+        assert!(!combos.swallow(2));
+    }
+
+    #[test]
+    fn falls_back_after_the_timeout() {
+        let mut combos = Combos::new();
+        let mut queue = script(&[(0, 1, true)]);
+        assert!(pending(combos.decide(&mut queue, &COMBOS, 50)));
+        assert!(no_match(combos.decide(&mut queue, &COMBOS, 51)));
+
+        let mut queue = script(&[(0, 1, true), (51, 2, true)]);
+        assert!(no_match(combos.decide(&mut queue, &COMBOS, 51)));
+        assert_eq!(events(&queue)[1], Some((2, true)));
+    }
+
+    #[test]
+    fn interrupted_nesteddo_not_match() {
+        let mut combos = Combos::new();
+        // released before the other key went down
+        let mut queue = script(&[(0, 1, true), (10, 1, false), (20, 2, true)]);
+        assert!(no_match(combos.decide(&mut queue, &COMBOS, 20)));
+        // a key outside the combo pressed in between
+        let mut queue = script(&[(0, 1, true), (10, 9, true), (20, 2, true)]);
+        assert!(no_match(combos.decide(&mut queue, &COMBOS, 20)));
+        // keys of different combos
+        let mut queue = script(&[(0, 1, true), (10, 3, true)]);
+        assert!(no_match(combos.decide(&mut queue, &COMBOS, 10)));
+    }
+
+    #[test]
+    fn on_release_waits_for_all_keys_to_go_up() {
+        let mut combos = Combos::new();
+        let mut queue = script(&[(0, 3, true), (10, 4, true), (20, 5, true)]);
+        assert!(pending(combos.decide(&mut queue, &COMBOS, 100)));
+        for &(time, key) in [(110, 4), (120, 3)].iter() {
+            let event = KeyEvent {
+                key,
+                pressed: false,
+                time,
+            };
+            assert!(queue.push(event).is_ok());
+            assert!(pending(combos.decide(&mut queue, &COMBOS, time)));
+        }
+        let event = KeyEvent {
+            key: 5,
+            pressed: false,
+            time: 130,
+        };
+        assert!(queue.push(event).is_ok());
+        assert!(fired(combos.decide(&mut queue, &COMBOS, 130), TAB));
+
+        // only the first key is left, which taps the action
+        let expected = [Some((3, true)), Some((3, false)), None, None];
+        assert_eq!(events(&queue), expected);
+        assert!(!combos.swallow(4));
+        assert!(!combos.swallow(5));
+    }
+
+    #[test]
+    fn waits_for_a_bigger_combo() {
+        let nested = [
+            Combo {
+                keys: &[1, 2],
+                action: ESCAPE,
+                timeout: 50,
+                trigger: ComboTrigger::OnPress,
+            },
+            Combo {
+                keys: &[1, 2, 6],
+                action: TAB,
+                timeout: 50,
+                trigger: ComboTrigger::OnPress,
+            },
+        ];
+        let mut combos = Combos::new();
+        let mut queue = script(&[(0, 2, true), (10, 1, true)]);
+        assert!(pending(combos.decide(&mut queue, &nested, 50)));
+        assert!(fired(combos.decide(&mut queue, &nested, 51), ESCAPE));
+
+        let mut queue = script(&[(0, 2, true), (10, 1, true), (20, 6, true)]);
+        assert!(fired(combos.decide(&mut queue, &nested, 20), TAB));
+        assert_eq!(events(&queue), [Some((2, true)), None, None, None]);
+    }
+}
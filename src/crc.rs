@@ -0,0 +1,30 @@
+/// CRC-32 as used by Ethernet and zlib, computed bit by bit to keep
+/// the firmware small
+pub struct Crc32 {
+    crc: u32,
+}
+
+impl Crc32 {
+    pub const fn new() -> Crc32 {
+        Crc32 { crc: 0xffff_ffff }
+    }
+
+    pub fn update(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.crc ^= u32::from(byte);
+            for _ in 0..8 {
+                let mask = (self.crc & 1).wrapping_neg();
+                self.crc = (self.crc >> 1) ^ (0xedb8_8320 & mask);
+            }
+        }
+    }
+
+    /// Add `word` as stored in memory, least significant byte first.
+    pub fn update_word(&mut self, word: u32) {
+        self.update(&word.to_le_bytes());
+    }
+
+    pub fn finish(&self) -> u32 {
+        !self.crc
+    }
+}
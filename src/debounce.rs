@@ -0,0 +1,181 @@
+use crate::keymatrix::{KeyState, COLUMNS, ROWS};
+use bit_field::BitArray;
+
+#[allow(dead_code)]
+#[derive(Copy, Clone, PartialEq)]
+pub enum DebounceMode {
+    /// Take over the whole matrix once no key changed for the debounce
+    /// time
+    SymmetricDefer,
+    /// Report a press at once, and a release once the key stayed up
+    /// for the debounce time
+    EagerPressDeferRelease,
+    /// Report a change of a key once it held for the debounce time,
+    /// counting separately for each key
+    PerKeyDefer,
+}
+
+pub struct DebounceConfig {
+    pub mode: DebounceMode,
+    /// Milliseconds a change must be stable for
+    pub time: u8,
+}
+
+/// Filters contact bounce out of raw matrix samples
+pub struct Debouncer {
+    config: &'static DebounceConfig,
+    /// Milliseconds each key differed from the debounced state
+    counters: [u8; COLUMNS * ROWS],
+    /// Milliseconds the whole matrix was stable, for `SymmetricDefer`
+    global: u16,
+    /// Previous raw sample, for `SymmetricDefer`
+    previous: KeyState,
+}
+
+impl Debouncer {
+    pub const fn new(config: &'static DebounceConfig) -> Debouncer {
+        Debouncer {
+            config,
+            counters: [0; COLUMNS * ROWS],
+            global: 0,
+            previous: [0; 9],
+        }
+    }
+
+    /// Update the debounced `state` with the `raw` sample taken `ms`
+    /// milliseconds after the previous one.
+    pub fn update(&mut self, raw: &KeyState, state: &mut KeyState, ms: u8) {
+        let time = self.config.time;
+        match self.config.mode {
+            DebounceMode::SymmetricDefer => {
+                if *raw != self.previous {
+                    self.previous = *raw;
+                    self.global = 0;
+                } else {
+                    self.global = self.global.saturating_add(u16::from(ms));
+                }
+                if self.global >= u16::from(time) {
+                    *state = *raw;
+                }
+            }
+            DebounceMode::EagerPressDeferRelease => {
+                for key in 0..COLUMNS * ROWS {
+                    if raw.get_bit(key) {
+                        state.set_bit(key, true);
+                        self.counters[key] = 0;
+                    } else if state.get_bit(key) {
+                        self.counters[key] = self.counters[key].saturating_add(ms);
+                        if self.counters[key] >= time {
+                            state.set_bit(key, false);
+                            self.counters[key] = 0;
+                        }
+                    }
+                }
+            }
+            DebounceMode::PerKeyDefer => {
+                for key in 0..COLUMNS * ROWS {
+                    if raw.get_bit(key) == state.get_bit(key) {
+                        self.counters[key] = 0;
+                    } else {
+                        self.counters[key] = self.counters[key].saturating_add(ms);
+                        if self.counters[key] >= time {
+                            state.set_bit(key, raw.get_bit(key));
+                            self.counters[key] = 0;
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: usize = 5;
+    const OTHER: usize = 40;
+    const T: bool = true;
+    const F: bool = false;
+
+    const SYMMETRIC: DebounceConfig = DebounceConfig {
+        mode: DebounceMode::SymmetricDefer,
+        time: 5,
+    };
+    const EAGER: DebounceConfig = DebounceConfig {
+        mode: DebounceMode::EagerPressDeferRelease,
+        time: 5,
+    };
+    const PER_KEY: DebounceConfig = DebounceConfig {
+        mode: DebounceMode::PerKeyDefer,
+        time: 5,
+    };
+
+    /// Feed `debouncer` samples of `KEY` taken 3ms apart, along with
+    /// `other` for `OTHER`, and check the debounced `KEY` after each.
+    fn check(debouncer: &mut Debouncer, samples: &[(bool, bool, bool)]) {
+        let mut state = [0; 9];
+        for (i, &(pressed, other, expected)) in samples.iter().enumerate() {
+            let mut raw = [0; 9];
+            raw.set_bit(KEY, pressed);
+            raw.set_bit(OTHER, other);
+            debouncer.update(&raw, &mut state, 3);
+            assert_eq!(state.get_bit(KEY), expected, "sample {}", i);
+        }
+    }
+
+    #[test]
+    fn symmetric_defer_waits_for_the_whole_matrix() {
+        let mut debouncer = Debouncer::new(&SYMMETRIC);
+        check(
+            &mut debouncer,
+            &[
+                (T, F, F),
+                (F, F, F),
+                (T, F, F),
+                (T, F, F),
+                (T, F, T),
+                // another key bouncing holds back the release
+                (F, T, T),
+                (F, F, T),
+                (F, T, T),
+                (F, T, T),
+                (F, T, F),
+            ],
+        );
+    }
+
+    #[test]
+    fn eager_press_defer_release() {
+        let mut debouncer = Debouncer::new(&EAGER);
+        check(
+            &mut debouncer,
+            &[
+                (T, F, T),
+                (F, F, T),
+                (T, F, T),
+                (F, F, T),
+                (F, F, F),
+                (F, F, F),
+            ],
+        );
+    }
+
+    #[test]
+    fn per_key_defer() {
+        let mut debouncer = Debouncer::new(&PER_KEY);
+        check(
+            &mut debouncer,
+            &[
+                (T, F, F),
+                (F, T, F),
+                (T, F, F),
+                (T, T, T),
+                (F, F, T),
+                (T, F, T),
+                (F, T, T),
+                (F, F, F),
+            ],
+        );
+    }
+}
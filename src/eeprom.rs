@@ -0,0 +1,224 @@
+use core::ptr;
+
+use stm32l1::stm32l151::FLASH;
+
+use crate::record_log::Storage;
+
+/// Start of the data EEPROM in the address space
+const BASE: usize = 0x0808_0000;
+/// Size of the data EEPROM in bytes
+pub const SIZE: usize = 4096;
+
+// Layout of the data EEPROM, as byte offsets
+/// Header word and steps of the recorded macro, see
+/// [`macros::MacroRecorder`]
+pub const RECORDED_MACRO: usize = 0;
+/// Default layer chosen with `Action::SetDefaultLayer`
+pub const DEFAULT_LAYER: usize = 0x200;
+/// Runtime keymap, see [`keymap::Keymap`]
+pub const KEYMAP: usize = 0x400;
+/// Record log of the settings, see [`settings::SettingsStore`]
+pub const SETTINGS_LOG: usize = 0xc00;
+pub const SETTINGS_LOG_SIZE: usize = 0x400;
+
+const PEKEY1: u32 = 0x89AB_CDEF;
+const PEKEY2: u32 = 0x0203_0405;
+const PRGKEY1: u32 = 0x8C9D_AEBF;
+const PRGKEY2: u32 = 0x1314_1516;
+
+const PECR_PELOCK: u32 = 1 << 0;
+const PECR_PRGLOCK: u32 = 1 << 1;
+const PECR_PROG: u32 = 1 << 3;
+const PECR_ERASE: u32 = 1 << 9;
+const PECR_FPRG: u32 = 1 << 10;
+
+const SR_BSY: u32 = 1 << 0;
+const SR_WRPERR: u32 = 1 << 8;
+const SR_PGAERR: u32 = 1 << 9;
+const SR_SIZERR: u32 = 1 << 10;
+const SR_OPTVERR: u32 = 1 << 11;
+
+/// Start of the program flash kept for storage, the `STORAGE` region
+/// of `memory-release.x` past the end of the firmware image
+const FLASH_STORAGE: usize = 0x0800_F000;
+/// Size of the program flash kept for storage in bytes
+pub const FLASH_STORAGE_SIZE: usize = 0x1000;
+/// Program flash is erased a page at a time
+pub const PAGE_SIZE: usize = 256;
+/// Program flash is written half a page at a time
+pub const HALF_PAGE_WORDS: usize = 32;
+
+#[derive(Debug)]
+pub enum EepromError {
+    /// The offset is not aligned, or outside the EEPROM or the
+    /// program flash kept for storage
+    OutOfRange,
+    WriteProtected,
+    Alignment,
+    Size,
+    /// The option bytes do not check out
+    OptionBytes,
+}
+
+/// Word access to the data EEPROM, and page access to the program
+/// flash kept for storage, through the flash program and erase
+/// controller
+pub struct Eeprom {
+    flash: FLASH,
+}
+
+impl Eeprom {
+    pub fn new(flash: FLASH) -> Eeprom {
+        Eeprom { flash }
+    }
+
+    pub fn read_word(&self, offset: usize) -> u32 {
+        if offset % 4 != 0 || offset >= SIZE {
+            return 0;
+        }
+        unsafe { ptr::read_volatile((BASE + offset) as *const u32) }
+    }
+
+    /// Start writing `word` at `offset`.
+    ///
+    /// Programming takes a few milliseconds, during which further
+    /// writes return `WouldBlock`. Errors of the previous write are
+    /// reported by the next call.
+    pub fn write_word(&mut self, offset: usize, word: u32) -> nb::Result<(), EepromError> {
+        if offset % 4 != 0 || offset >= SIZE {
+            return Err(nb::Error::Other(EepromError::OutOfRange));
+        }
+        self.ready()?;
+
+        if self.read_word(offset) == word {
+            return Ok(());
+        }
+        self.unlock_data();
+        unsafe { ptr::write_volatile((BASE + offset) as *mut u32, word) };
+        Ok(())
+    }
+
+    /// Start erasing the page at `offset` into the program flash kept
+    /// for storage.
+    #[allow(dead_code)]
+    pub fn erase_page(&mut self, offset: usize) -> nb::Result<(), EepromError> {
+        if offset % PAGE_SIZE != 0 || offset >= FLASH_STORAGE_SIZE {
+            return Err(nb::Error::Other(EepromError::OutOfRange));
+        }
+        self.ready()?;
+
+        self.unlock_program();
+        self.flash
+            .pecr
+            .modify(|r, w| unsafe { w.bits(r.bits() | PECR_ERASE | PECR_PROG) });
+        // writing any word of the page starts the erase
+        unsafe { ptr::write_volatile((FLASH_STORAGE + offset) as *mut u32, 0) };
+        Ok(())
+    }
+
+    /// Start writing `words` to the erased half-page at `offset` into
+    /// the program flash kept for storage.
+    #[allow(dead_code)]
+    pub fn write_half_page(
+        &mut self,
+        offset: usize,
+        words: &[u32; HALF_PAGE_WORDS],
+    ) -> nb::Result<(), EepromError> {
+        if offset % (4 * HALF_PAGE_WORDS) != 0 || offset >= FLASH_STORAGE_SIZE {
+            return Err(nb::Error::Other(EepromError::OutOfRange));
+        }
+        self.ready()?;
+
+        self.unlock_program();
+        self.flash
+            .pecr
+            .modify(|r, w| unsafe { w.bits(r.bits() | PECR_FPRG | PECR_PROG) });
+        // the flash can not be read until all words are latched, so
+        // neither interrupts nor the code running from flash may get
+        // in between
+        cortex_m::interrupt::free(|_| unsafe {
+            write_half_page((FLASH_STORAGE + offset) as *mut u32, words)
+        });
+        Ok(())
+    }
+
+    /// Lock the data EEPROM and the program flash against writes.
+    #[allow(dead_code)]
+    pub fn lock(&mut self) -> nb::Result<(), EepromError> {
+        self.ready()?;
+        self.flash
+            .pecr
+            .modify(|r, w| unsafe { w.bits(r.bits() | PECR_PELOCK) });
+        Ok(())
+    }
+
+    /// `WouldBlock` while the controller is busy, otherwise the errors
+    /// of the previous operation, which are cleared.
+    fn ready(&mut self) -> nb::Result<(), EepromError> {
+        let status = self.flash.sr.read().bits();
+        if status & SR_BSY != 0 {
+            return Err(nb::Error::WouldBlock);
+        }
+        // back to word writes to the data EEPROM
+        self.flash
+            .pecr
+            .modify(|r, w| unsafe { w.bits(r.bits() & !(PECR_PROG | PECR_ERASE | PECR_FPRG)) });
+
+        let errors = status & (SR_WRPERR | SR_PGAERR | SR_SIZERR | SR_OPTVERR);
+        if errors != 0 {
+            // error flags are cleared by writing 1
+            self.flash.sr.write(|w| unsafe { w.bits(errors) });
+            return Err(nb::Error::Other(if errors & SR_WRPERR != 0 {
+                EepromError::WriteProtected
+            } else if errors & SR_PGAERR != 0 {
+                EepromError::Alignment
+            } else if errors & SR_SIZERR != 0 {
+                EepromError::Size
+            } else {
+                EepromError::OptionBytes
+            }));
+        }
+        Ok(())
+    }
+
+    fn unlock_data(&mut self) {
+        if self.flash.pecr.read().bits() & PECR_PELOCK != 0 {
+            self.flash.pekeyr.write(|w| unsafe { w.bits(PEKEY1) });
+            self.flash.pekeyr.write(|w| unsafe { w.bits(PEKEY2) });
+        }
+    }
+
+    /// The program flash unlocks only after the data EEPROM.
+    fn unlock_program(&mut self) {
+        self.unlock_data();
+        if self.flash.pecr.read().bits() & PECR_PRGLOCK != 0 {
+            self.flash.prgkeyr.write(|w| unsafe { w.bits(PRGKEY1) });
+            self.flash.prgkeyr.write(|w| unsafe { w.bits(PRGKEY2) });
+        }
+    }
+}
+
+impl Storage for Eeprom {
+    type Error = EepromError;
+
+    fn read_word(&self, offset: usize) -> u32 {
+        Eeprom::read_word(self, offset)
+    }
+
+    fn write_word(&mut self, offset: usize, word: u32) -> nb::Result<(), EepromError> {
+        Eeprom::write_word(self, offset, word)
+    }
+}
+
+/// Latch the words of a half-page write. Runs from RAM, as fetching
+/// code from the flash would abort the write.
+#[inline(never)]
+#[link_section = ".data.write_half_page"]
+unsafe fn write_half_page(address: *mut u32, words: &[u32; HALF_PAGE_WORDS]) {
+    // a plain loop, leaving no calls into code in the flash
+    let mut i = 0;
+    while i < HALF_PAGE_WORDS {
+        ptr::write_volatile(address.add(i), *words.get_unchecked(i));
+        i += 1;
+    }
+}
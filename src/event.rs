@@ -0,0 +1,186 @@
+use crate::keymatrix::{COLUMNS, ROWS};
+
+/// A single key press or release picked up by the matrix scan
+#[derive(Copy, Clone, PartialEq)]
+pub struct KeyEvent {
+    /// Position in the scan matrix, see [`keycodes::KeyIndex`]
+    pub key: u8,
+    pub pressed: bool,
+    /// Milliseconds since boot when the change was sampled (wrapping)
+    pub time: u32,
+}
+
+impl KeyEvent {
+    const fn empty() -> KeyEvent {
+        KeyEvent {
+            key: 0,
+            pressed: false,
+            time: 0,
+        }
+    }
+}
+
+/// Number of keys that events can refer to: the ones in the scan
+/// matrix, followed by keys only pressed by the firmware itself
+pub const KEY_COUNT: usize = COLUMNS * ROWS + 1;
+
+/// Pressed with the action of a leader sequence that ended by timing
+/// out, as no physical key is left to carry it
+pub const LEADER_KEY: u8 = (COLUMNS * ROWS) as u8;
+
+/// Milliseconds passed between `since` and `now`, robust against
+/// the millisecond counter wrapping around
+pub fn elapsed(now: u32, since: u32) -> u32 {
+    now.wrapping_sub(since)
+}
+
+pub const QUEUE_SIZE: usize = 16;
+
+/// Fixed-size FIFO of key events that have been sampled but not yet
+/// handed to the event processors
+pub struct EventQueue {
+    events: [KeyEvent; QUEUE_SIZE],
+    /// Index of the oldest event in `events`
+    head: usize,
+    len: usize,
+}
+
+impl EventQueue {
+    pub const fn new() -> EventQueue {
+        EventQueue {
+            events: [KeyEvent::empty(); QUEUE_SIZE],
+            head: 0,
+            len: 0,
+        }
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.len == QUEUE_SIZE
+    }
+
+    /// Number of events that can still be pushed
+    pub fn space(&self) -> usize {
+        QUEUE_SIZE - self.len
+    }
+
+    /// Append `event`, handing it back if there is no room left
+    pub fn push(&mut self, event: KeyEvent) -> Result<(), KeyEvent> {
+        if self.is_full() {
+            return Err(event);
+        }
+        self.events[(self.head + self.len) % QUEUE_SIZE] = event;
+        self.len += 1;
+        Ok(())
+    }
+
+    pub fn peek(&self) -> Option<KeyEvent> {
+        self.get(0)
+    }
+
+    /// The `i`-th oldest event
+    pub fn get(&self, i: usize) -> Option<KeyEvent> {
+        if i < self.len {
+            Some(self.events[(self.head + i) % QUEUE_SIZE])
+        } else {
+            None
+        }
+    }
+
+    pub fn pop(&mut self) -> Option<KeyEvent> {
+        let event = self.peek()?;
+        self.head = (self.head + 1) % QUEUE_SIZE;
+        self.len -= 1;
+        Some(event)
+    }
+
+    /// Remove the `i`-th oldest event, keeping the others in order
+    pub fn remove(&mut self, i: usize) {
+        if i < self.len {
+            for j in i..self.len - 1 {
+                self.events[(self.head + j) % QUEUE_SIZE] =
+                    self.events[(self.head + j + 1) % QUEUE_SIZE];
+            }
+            self.len -= 1;
+        }
+    }
+
+    /// Iterate from the oldest to the newest event
+    pub fn iter(&self) -> impl Iterator<Item = KeyEvent> + '_ {
+        (0..self.len).map(move |i| self.events[(self.head + i) % QUEUE_SIZE])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(key: u8, pressed: bool, time: u32) -> KeyEvent {
+        KeyEvent { key, pressed, time }
+    }
+
+    fn keys(queue: &EventQueue) -> [Option<u8>; QUEUE_SIZE] {
+        let mut keys = [None; QUEUE_SIZE];
+        for (key, event) in keys.iter_mut().zip(queue.iter()) {
+            *key = Some(event.key);
+        }
+        keys
+    }
+
+    #[test]
+    fn elapsed_wraps() {
+        assert_eq!(elapsed(10, 4), 6);
+        assert_eq!(elapsed(2, u32::max_value() - 1), 4);
+    }
+
+    #[test]
+    fn queue_is_fifo() {
+        let mut queue = EventQueue::new();
+        assert!(queue.pop().is_none());
+        for key in 0..QUEUE_SIZE as u8 {
+            assert!(queue.push(event(key, true, u32::from(key))).is_ok());
+        }
+        assert!(queue.is_full());
+        assert_eq!(queue.space(), 0);
+        assert!(queue.push(event(99, true, 0)) == Err(event(99, true, 0)));
+
+        for key in 0..QUEUE_SIZE as u8 {
+            assert!(queue.pop().map(|event| event.key) == Some(key));
+        }
+        assert!(queue.peek().is_none());
+    }
+
+    #[test]
+    fn queue_wraps_around() {
+        let mut queue = EventQueue::new();
+        for round in 0..3 * QUEUE_SIZE as u32 {
+            assert!(queue.push(event(1, true, round)).is_ok());
+            assert!(queue.push(event(2, false, round)).is_ok());
+            assert!(queue.pop() == Some(event(1, true, round)));
+            assert!(queue.get(0) == Some(event(2, false, round)));
+            assert!(queue.pop().is_some());
+        }
+        assert_eq!(queue.space(), QUEUE_SIZE);
+    }
+
+    #[test]
+    fn remove_keeps_order() {
+        let mut queue = EventQueue::new();
+        // start past the end of the buffer, so removing wraps around
+        for _ in 0..QUEUE_SIZE - 2 {
+            assert!(queue.push(event(0, true, 0)).is_ok());
+            queue.pop();
+        }
+        for key in 1..=5 {
+            assert!(queue.push(event(key, true, 0)).is_ok());
+        }
+        queue.remove(1);
+        queue.remove(10);
+        let mut expected = [None; QUEUE_SIZE];
+        expected[..4].copy_from_slice(&[Some(1), Some(3), Some(4), Some(5)]);
+        assert_eq!(keys(&queue), expected);
+        queue.remove(3);
+        queue.remove(0);
+        expected[..4].copy_from_slice(&[Some(3), Some(4), None, None]);
+        assert_eq!(keys(&queue), expected);
+    }
+}
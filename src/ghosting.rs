@@ -0,0 +1,106 @@
+use crate::keymatrix::{KeyState, COLUMNS, ROWS};
+use bit_field::BitArray;
+
+/// Keeps back key presses that could be ghosts.
+///
+/// Without a diode per key, three pressed keys at three corners of a
+/// rectangle in the matrix make the fourth corner read as pressed as
+/// well. A newly pressed key on a rectangle of pressed keys is
+/// therefore not reported until the rectangle is gone.
+pub struct GhostFilter {
+    /// Number of key presses held back so far
+    blocked: u32,
+    /// Keys held back in the last scan
+    blocking: KeyState,
+}
+
+impl GhostFilter {
+    pub const fn new() -> GhostFilter {
+        GhostFilter {
+            blocked: 0,
+            blocking: [0; 9],
+        }
+    }
+
+    /// Number of key presses held back since reset (wrapping)
+    pub fn blocked(&self) -> u32 {
+        self.blocked
+    }
+
+    /// Update the reported `state` from the `debounced` scan.
+    pub fn filter(&mut self, debounced: &KeyState, state: &mut KeyState) {
+        let mut blocking: KeyState = [0; 9];
+        for key in 0..COLUMNS * ROWS {
+            let pressed = debounced.get_bit(key);
+            if pressed && !state.get_bit(key) && on_rectangle(debounced, key) {
+                blocking.set_bit(key, true);
+                if !self.blocking.get_bit(key) {
+                    self.blocked = self.blocked.wrapping_add(1);
+                }
+            } else {
+                state.set_bit(key, pressed);
+            }
+        }
+        self.blocking = blocking;
+    }
+}
+
+/// Whether `key` is a corner of a rectangle of keys pressed in `keys`
+fn on_rectangle(keys: &KeyState, key: usize) -> bool {
+    let (row, column) = (key / COLUMNS, key % COLUMNS);
+    (0..ROWS).filter(|&r| r != row).any(|r| {
+        keys.get_bit(r * COLUMNS + column)
+            && (0..COLUMNS)
+                .filter(|&c| c != column)
+                .any(|c| keys.get_bit(row * COLUMNS + c) && keys.get_bit(r * COLUMNS + c))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn holds_back_the_fourth_corner() {
+        let mut filter = GhostFilter::new();
+        let mut state = [0; 9];
+        let mut debounced = [0; 9];
+        // the first keys of the first two rows
+        for &key in [0, 1, COLUMNS].iter() {
+            debounced.set_bit(key, true);
+            filter.filter(&debounced, &mut state);
+            assert!(state.get_bit(key));
+        }
+        debounced.set_bit(COLUMNS + 1, true);
+        filter.filter(&debounced, &mut state);
+        filter.filter(&debounced, &mut state);
+        assert!(!state.get_bit(COLUMNS + 1));
+        assert_eq!(filter.blocked(), 1);
+
+        // reported once the rectangle is gone
+        debounced.set_bit(0, false);
+        filter.filter(&debounced, &mut state);
+        assert!(state.get_bit(COLUMNS + 1));
+        assert!(!state.get_bit(0));
+        assert_eq!(filter.blocked(), 1);
+    }
+
+    #[test]
+    fn keeps_keys_reported_before() {
+        let mut filter = GhostFilter::new();
+        let mut state = [0; 9];
+        let mut debounced = [0; 9];
+        for &key in [0, 1, COLUMNS, COLUMNS + 1].iter() {
+            debounced.set_bit(key, true);
+        }
+        // a whole rectangle pressed in the same scan is held back
+        filter.filter(&debounced, &mut state);
+        assert_eq!(state, [0; 9]);
+        assert_eq!(filter.blocked(), 4);
+
+        state = debounced;
+        filter.filter(&debounced, &mut state);
+        assert_eq!(state, debounced);
+        assert_eq!(filter.blocked(), 4);
+    }
+}
@@ -1,7 +1,10 @@
+use bit_field::BitArray;
 use core::slice;
 
+use crate::keycodes::KeyCode;
+
 #[repr(packed)]
-#[derive(Default)]
+#[derive(Copy, Clone, Default)]
 pub struct HidReport {
     pub modifiers: u8,
     _unused: u8,
@@ -9,6 +12,39 @@ pub struct HidReport {
 }
 
 impl HidReport {
+    pub const fn new() -> HidReport {
+        HidReport {
+            modifiers: 0,
+            _unused: 0,
+            keys: [0; 6],
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.modifiers == 0 && self.keys.iter().all(|&key| key == 0)
+    }
+
+    /// Add `code` to the report, if there is room for it
+    pub fn press(&mut self, code: KeyCode) {
+        if code.is_modifier() {
+            self.modifiers |= code.modifier_mask();
+        } else if !self.keys.contains(&(code as u8)) {
+            if let Some(slot) = self.keys.iter_mut().find(|key| **key == 0) {
+                *slot = code as u8;
+            }
+        }
+    }
+
+    pub fn release(&mut self, code: KeyCode) {
+        if code.is_modifier() {
+            self.modifiers &= !code.modifier_mask();
+        } else if let Some(i) = self.keys.iter().position(|&key| key == code as u8) {
+            // keep the pressed keys at the front
+            self.keys[i..].rotate_left(1);
+            self.keys[5] = 0;
+        }
+    }
+
     pub fn as_bytes(&self) -> &[u8] {
         unsafe {
             let p: *const HidReport = self;
@@ -16,3 +52,53 @@ impl HidReport {
         }
     }
 }
+
+/// N-key rollover report, sent over USB in place of [`HidReport`]
+#[repr(packed)]
+#[derive(Copy, Clone, Default)]
+pub struct NkroReport {
+    pub modifiers: u8,
+    /// One bit per usage from 0x00 to 0xDF
+    pub keys: [u8; 28],
+}
+
+impl NkroReport {
+    pub const fn new() -> NkroReport {
+        NkroReport {
+            modifiers: 0,
+            keys: [0; 28],
+        }
+    }
+
+    pub fn press(&mut self, code: KeyCode) {
+        if code.is_modifier() {
+            self.modifiers |= code.modifier_mask();
+        } else {
+            self.keys.set_bit(code as usize, true);
+        }
+    }
+
+    /// Add the modifiers and keys of the 6-key `report`.
+    pub fn add(&mut self, report: &HidReport) {
+        self.modifiers |= report.modifiers;
+        for &key in report.keys.iter().filter(|&&key| key != 0) {
+            self.keys.set_bit(key as usize, true);
+        }
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        unsafe {
+            let p: *const NkroReport = self;
+            slice::from_raw_parts(p as *const u8, 29)
+        }
+    }
+}
+
+/// Relative mouse report, see [`mouse::MouseKeys`]
+#[derive(Copy, Clone, Default)]
+pub struct MouseReport {
+    pub buttons: u8,
+    pub x: i8,
+    pub y: i8,
+    pub wheel: i8,
+}
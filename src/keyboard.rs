@@ -1,28 +1,89 @@
 use crate::action::Action;
 use crate::bluetooth::Bluetooth;
+use crate::clock::TICK_MS;
+use crate::combo::{ComboState, Combos};
 use crate::debug::UnwrapLog;
-use crate::hidreport::HidReport;
-use crate::keycodes::KeyCode;
+use crate::eeprom::{Eeprom, EepromError, DEFAULT_LAYER};
+use crate::event::{elapsed, KeyEvent, KEY_COUNT, LEADER_KEY};
+use crate::hidreport::{HidReport, NkroReport};
+use crate::keycodes::{KeyCode, SystemKey};
+use crate::keymap::{Keymap, KeymapError};
 use crate::keymatrix::{KeyState, COLUMNS, ROWS};
-use crate::layout::LAYERS;
-use crate::layout::{LAYER_BT, LAYER_FN};
+use crate::layout::{
+    AUTO_SHIFT, COMBOS, CONDITIONAL_LAYERS, KEY_OVERRIDES, LAYER_BT, LAYER_COUNT, LAYER_FN,
+    LEADER_SEQUENCES, LEADER_TIMEOUT, MACROS, MOUSE_KEYS, SAVE_RECORDED_MACRO, TAPPING, TAP_DANCES,
+};
+use crate::leader::Leader;
 use crate::led::Led;
+use crate::macros::{MacroPlayer, MacroRecorder, MacroStep};
+use crate::mouse::MouseKeys;
+use crate::settings::{Settings, SettingsStore};
+use crate::tapping::Tapping;
 use crate::usb::Usb;
 use bit_field::{BitArray, BitField};
 use core::marker::Unsize;
 use stm32l1::stm32l151::SCB;
 
 pub struct Keyboard {
+    /// Layers the actions are looked up in
+    pub keymap: Keymap,
+    /// Settings kept across resets, see `sync_settings`
+    pub settings: SettingsStore,
     layers: Layers,
+    one_shot_mods: OneShotMods,
+    caps_word: CapsWord,
+    combos: Combos,
+    tapping: Tapping,
+    leader: Leader,
+    macros: MacroPlayer,
+    mouse: MouseKeys,
+    recorder: MacroRecorder,
+    /// Matrix state from the previous scan
+    previous_matrix: KeyState,
+    /// Keys as seen by the event processors, which can lag behind the
+    /// matrix while tap-hold keys are undecided
+    state: KeyState,
     previous_state: KeyState,
+    /// Action each key resolved to when it was pressed. It sticks
+    /// until the key is released, so that a layer change can not
+    /// change what holding or releasing the key does.
+    actions: [Action; KEY_COUNT],
+    /// Keys whose current press has its action in `actions`
+    resolved: KeyState,
+    /// Report for the keys in `state`, without the keys held by a
+    /// macro
+    report: HidReport,
+    /// Keys in `state` that did not fit into `report`
+    rollover: NkroReport,
+    /// Milliseconds since boot (wrapping)
+    now: u32,
+    auto_shift: bool,
     pub send_usb_report: bool,
 }
 
 impl Keyboard {
     pub const fn new() -> Keyboard {
         Keyboard {
+            keymap: Keymap::new(),
+            settings: SettingsStore::new(),
             layers: Layers::new(),
+            one_shot_mods: OneShotMods::new(),
+            caps_word: CapsWord::new(),
+            combos: Combos::new(),
+            tapping: Tapping::new(),
+            leader: Leader::new(),
+            macros: MacroPlayer::new(),
+            mouse: MouseKeys::new(),
+            recorder: MacroRecorder::new(),
+            previous_matrix: [0; 9],
+            state: [0; 9],
             previous_state: [0; 9],
+            actions: [Action::Nop; KEY_COUNT],
+            resolved: [0; 9],
+            report: HidReport::new(),
+            rollover: NkroReport::new(),
+            now: 0,
+            auto_shift: AUTO_SHIFT.enabled,
             send_usb_report: true,
         }
     }
@@ -33,10 +94,14 @@ impl Keyboard {
     /// currently active layers is returned.
     fn get_action(&self, key: usize) -> Action {
         let mut action = Action::Transparent;
+        if key >= COLUMNS * ROWS {
+            // keys beyond the matrix always carry a resolved action
+            return Action::Nop;
+        }
 
-        for i in (0..LAYERS.len()).rev() {
+        for i in (0..LAYER_COUNT).rev() {
             if self.layers.current.get_bit(i) {
-                action = LAYERS[i][key];
+                action = self.keymap.layer(i)[key];
             }
             if action != Action::Transparent {
                 break;
@@ -46,27 +111,134 @@ impl Keyboard {
         action
     }
 
+    /// Remember that the current press of `key` stands for `action`.
+    fn resolve(&mut self, key: usize, action: Action) {
+        self.actions[key] = action;
+        self.resolved.set_bit(key, true);
+    }
+
+    /// The action of the current press of `key`, as resolved when it
+    /// was pressed.
+    fn action(&self, key: usize) -> Action {
+        if self.resolved.get_bit(key) {
+            self.actions[key]
+        } else {
+            self.get_action(key)
+        }
+    }
+
+    /// Queue up the changes between `matrix` and the previous scan.
+    fn queue_events(&mut self, matrix: &KeyState) {
+        for key in 0..COLUMNS * ROWS {
+            let pressed = matrix.get_bit(key);
+            if self.previous_matrix.get_bit(key) != pressed {
+                let event = KeyEvent {
+                    key: key as u8,
+                    pressed,
+                    time: self.now,
+                };
+                // If the queue is full, the change is picked up
+                // again on the next scan
+                if self.tapping.queue.push(event).is_ok() {
+                    self.previous_matrix.set_bit(key, pressed);
+                }
+            }
+        }
+    }
+
+    /// Apply queued events to `state` until the queue is empty or
+    /// waiting on a tap-hold decision.
+    fn dispatch_events(&mut self) {
+        // Keys already changed in this scan. A key is changed at
+        // most once per scan so that every tap makes it into a report.
+        let mut changed: KeyState = [0; 9];
+
+        while let Some(event) = self.tapping.queue.peek() {
+            let key = event.key as usize;
+            // Processors see the keys of a scan in matrix order, so a
+            // press must not share its scan with earlier releases
+            if changed.get_bit(key) || (event.pressed && changed != [0; 9]) {
+                break;
+            }
+            if !event.pressed && self.combos.swallow(key) {
+                self.tapping.queue.pop();
+                continue;
+            }
+
+            if event.pressed {
+                match self
+                    .combos
+                    .decide(&mut self.tapping.queue, &COMBOS, self.now)
+                {
+                    ComboState::Pending => break,
+                    ComboState::Fired(action) => self.resolve(key, action),
+                    ComboState::NoMatch => {
+                        let action = self.get_action(key);
+                        let auto_shift = self.auto_shift && AUTO_SHIFT.applies(action);
+                        if action.is_tap_hold() || auto_shift {
+                            let decision = if auto_shift {
+                                self.tapping.auto_shift(action, &AUTO_SHIFT, self.now)
+                            } else {
+                                self.tapping.decide(action, &TAPPING, &TAP_DANCES, self.now)
+                            };
+                            match decision {
+                                Some(action) => self.resolve(key, action),
+                                None => break,
+                            }
+                        }
+                    }
+                }
+
+                let action = self.action(key);
+                let action = self
+                    .leader
+                    .capture(action, &LEADER_SEQUENCES, self.now)
+                    .unwrap_or(action);
+                self.resolve(key, action);
+            }
+            self.state.set_bit(key, event.pressed);
+            changed.set_bit(key, true);
+            self.tapping.queue.pop();
+            if event.pressed {
+                // Give each press a report of its own, and let layer
+                // changes take effect before the next key is looked up
+                break;
+            }
+        }
+    }
+
     pub fn process<BUFFER>(
         &mut self,
-        state: &KeyState,
+        matrix: &KeyState,
         bluetooth: &mut Bluetooth<BUFFER>,
         led: &mut Led<BUFFER>,
         usb: &mut Usb,
     ) where
         BUFFER: Unsize<[u8]>,
     {
-        // TODO: might not even need this check after switching to wakeup only handling?
-        if &self.previous_state != state {
+        self.now = self.now.wrapping_add(TICK_MS);
+        self.layers.tick(self.now);
+        self.queue_events(matrix);
+        if let Some(action) = self
+            .leader
+            .expire(&LEADER_SEQUENCES, LEADER_TIMEOUT, self.now)
+        {
+            self.tap_leader_key(action);
+        }
+        self.dispatch_events();
+
+        let mut send_report = false;
+        if self.previous_state != self.state {
             let mut hid = HidProcessor::default();
 
-            for key in 0..COLUMNS * ROWS {
-                let pressed = state.get_bit(key);
+            for key in 0..KEY_COUNT {
+                let pressed = self.state.get_bit(key);
                 let changed = self.previous_state.get_bit(key) != pressed;
 
                 // Only handle currently pressed and changed keys to
                 // cut down on processing time.
                 if pressed || changed {
-                    let action = self.get_action(key);
+                    let action = self.action(key);
                     if pressed && Action::Reset == action {
                         crate::heprintln!("system reset").ok();
                         SCB::sys_reset()
@@ -75,10 +247,34 @@ impl Keyboard {
                         self.send_usb_report = !self.send_usb_report;
                         crate::heprintln!("send_usb_report: {:?}", self.send_usb_report).ok();
                     }
+                    if pressed && changed && Action::NkroToggle == action {
+                        usb.toggle_nkro();
+                    }
+                    if pressed && changed && Action::System(SystemKey::WakeUp) == action {
+                        usb.wake_up();
+                    }
+                    if pressed && changed && Action::AutoShiftToggle == action {
+                        self.auto_shift = !self.auto_shift;
+                    }
+                    if pressed
+                        && changed
+                        && Action::DynMacroPlay == action
+                        && !self.recorder.is_recording()
+                    {
+                        self.macros.play_recorded(self.recorder.steps().len());
+                    }
                     hid.process(&action, pressed, changed);
                     led.process(&action, pressed, changed);
                     bluetooth.process(&action, pressed, changed);
-                    self.layers.process(&action, pressed, changed);
+                    self.layers.process_key(key, &action, pressed, changed);
+                    self.one_shot_mods.process(&action, pressed, changed);
+                    self.caps_word.process(&action, pressed, changed);
+                    self.macros.process(&action, pressed, changed);
+                    self.recorder.process(&action, pressed, changed);
+                    self.mouse.process(&action, pressed, changed);
+                    if !pressed {
+                        self.resolved.set_bit(key, false);
+                    }
                 }
             }
 
@@ -89,11 +285,12 @@ impl Keyboard {
             {
                 let mut buffer = [0xcau8; 25 * 5 + 2];
                 let payload_length = super::theme::layout_to_theme(
-                    &super::layout::FN,
+                    self.keymap.layer(LAYER_FN as usize),
                     0,
                     bluetooth.connected_host,
                     bluetooth.mode,
                     self.send_usb_report,
+                    self.caps_word.active,
                 )
                 .fill_payload(&mut buffer);
                 led.set_keys(&buffer[..payload_length]).log_error();
@@ -102,24 +299,132 @@ impl Keyboard {
             } else {
                 let mut buffer = [0xcau8; 25 * 5 + 2];
                 let payload_length = super::theme::layout_to_theme(
-                    &super::layout::BASE,
+                    self.keymap.layer(self.layers.default as usize),
                     0,
                     bluetooth.connected_host,
                     bluetooth.mode,
                     self.send_usb_report,
+                    self.caps_word.active,
                 )
                 .fill_payload(&mut buffer);
                 led.set_keys(&buffer[..payload_length]).log_error();
             }
 
             self.layers.finish();
+            self.mouse.finish(self.now);
+            hid.report.modifiers |= self.one_shot_mods.modifiers();
+            hid.report.modifiers |= self.caps_word.modifiers();
+            for key_override in KEY_OVERRIDES.iter() {
+                key_override.apply(&mut hid.report, &mut hid.rollover);
+            }
+            self.one_shot_mods.finish();
+            self.report = hid.report;
+            self.rollover = hid.rollover;
+            if self.send_usb_report {
+                usb.update_consumer_report(hid.consumer);
+                usb.update_system_report(hid.system);
+            }
+            send_report = true;
+
+            self.previous_state = self.state;
+        }
 
-            bluetooth.send_report(&hid.report).log_error();
+        // A macro moves on by one step per report, and only once the
+        // report made it into the Bluetooth send buffer and, as USB
+        // only keeps the latest report, was picked up by the USB host
+        if self.macros.tick(self.now, self.recorder.steps()) || send_report {
+            let mut report = self.report;
+            self.macros.add_to(&mut report);
+            let result = bluetooth.send_report(&report);
+            if result.is_ok() {
+                self.macros.sent(self.send_usb_report);
+            }
+            result.log_error();
             if self.send_usb_report {
-                usb.update_report(&hid.report);
+                let mut nkro_report = self.rollover;
+                nkro_report.add(&report);
+                usb.update_report(&report, &nkro_report);
             }
+        }
+        if usb.report_sent() {
+            self.macros.usb_sent();
+        }
+
+        // Mouse keys keep moving the cursor while held, without any
+        // change in the matrix
+        if let Some(report) = self.mouse.tick(&MOUSE_KEYS, self.now) {
+            if self.send_usb_report {
+                usb.update_mouse_report(&report);
+            }
+        }
+
+        self.sync_settings(bluetooth, led);
+    }
 
-            self.previous_state = *state;
+    /// Take over changes to the settings kept in `settings`.
+    fn sync_settings<BUFFER>(&mut self, bluetooth: &Bluetooth<BUFFER>, led: &Led<BUFFER>)
+    where
+        BUFFER: Unsize<[u8]>,
+    {
+        let previous = self.settings.get();
+        let mut settings = Settings {
+            led_on: led.state,
+            led: led
+                .config
+                .or_else(|| previous.and_then(|settings| settings.led)),
+            send_usb_report: self.send_usb_report,
+            bluetooth_host: previous.map_or(0, |settings| settings.bluetooth_host),
+        };
+        if bluetooth.connected_host != 0 {
+            settings.bluetooth_host = bluetooth.connected_host;
+        }
+        self.settings.set(settings, self.now);
+    }
+
+    /// Restore the state saved in `eeprom`.
+    pub fn load(&mut self, eeprom: &Eeprom) {
+        self.keymap.load(eeprom);
+        self.settings.load(eeprom);
+        self.recorder.load(eeprom);
+        self.layers.load(eeprom);
+    }
+
+    /// Carry on saving state to `eeprom`, one word at a time.
+    pub fn save(&mut self, eeprom: &mut Eeprom) {
+        if let Err(nb::Error::Other(e)) = self.settings.save(eeprom, self.now) {
+            let result: Result<(), EepromError> = Err(e);
+            result.log_error();
+        }
+        if let Err(nb::Error::Other(e)) = self.keymap.save(eeprom) {
+            let result: Result<(), KeymapError> = Err(e);
+            result.log_error();
+        }
+        if let Err(nb::Error::Other(e)) = self.recorder.save(eeprom) {
+            let result: Result<(), EepromError> = Err(e);
+            result.log_error();
+        }
+        if let Err(nb::Error::Other(e)) = self.layers.save(eeprom) {
+            let result: Result<(), EepromError> = Err(e);
+            result.log_error();
+        }
+    }
+
+    /// Queue a tap of `LEADER_KEY` carrying `action`.
+    fn tap_leader_key(&mut self, action: Action) {
+        let press = KeyEvent {
+            key: LEADER_KEY,
+            pressed: true,
+            time: self.now,
+        };
+        let release = KeyEvent {
+            pressed: false,
+            ..press
+        };
+        // Both or neither, so the key can not get stuck
+        if self.tapping.queue.space() >= 2 {
+            self.resolve(LEADER_KEY as usize, action);
+            self.tapping.queue.push(press).ok();
+            self.tapping.queue.push(release).ok();
         }
     }
 
@@ -137,26 +442,150 @@ trait EventProcessor {
     fn finish(&mut self) {}
 }
 
+/// Layer activated while all of `if_layers` are, see
+/// `layout::CONDITIONAL_LAYERS`
+pub struct ConditionalLayer {
+    pub if_layers: [u8; 2],
+    pub then_layer: u8,
+}
+
+/// Marks a saved default layer in the EEPROM, along with the layer in
+/// the low byte
+const DEFAULT_LAYER_MAGIC: u32 = 0x444c_0000;
+
 /// Bit-field of the currently active layers, indexed by position in
 /// [`layout::LAYERS`].
 struct Layers {
     current: u8,
+    /// Base layer, which is always active
+    default: u8,
+    /// Whether `default` has yet to be saved
+    saving: bool,
     /// Active layers after action processing is finished
     next: u8,
+    /// One-shot layer keys that are currently held down
+    one_shot_held: u8,
+    /// Held one-shot layers that were used for another key, and so
+    /// act as momentary layers
+    one_shot_used: u8,
+    /// Tapped one-shot layers waiting for the next key press
+    one_shot_armed: u8,
+    /// One-shot layers used by a key press, which switch off when
+    /// that key is released
+    one_shot_consumed: u8,
+    /// The key whose press used up `one_shot_consumed`
+    one_shot_key: usize,
+    /// When `one_shot_armed` was last changed
+    one_shot_since: u32,
+    now: u32,
 }
 
 impl Layers {
     const fn new() -> Layers {
         Layers {
             current: 0b1,
+            default: 0,
+            saving: false,
             next: 0b1,
+            one_shot_held: 0,
+            one_shot_used: 0,
+            one_shot_armed: 0,
+            one_shot_consumed: 0,
+            one_shot_key: 0,
+            one_shot_since: 0,
+            now: 0,
+        }
+    }
+
+    /// Advance the clock, switching off one-shot layers that have
+    /// been waiting too long.
+    fn tick(&mut self, now: u32) {
+        self.now = now;
+        if self.one_shot_armed != 0 && elapsed(now, self.one_shot_since) >= TAPPING.one_shot_timeout
+        {
+            self.next &= !self.one_shot_armed;
+            self.one_shot_armed = 0;
+            self.update_current();
+        }
+    }
+
+    /// Activate the layers in `next`, along with the conditional
+    /// layers whose conditions they meet.
+    fn update_current(&mut self) {
+        self.current = self.next;
+        for conditional in CONDITIONAL_LAYERS.iter() {
+            let active = conditional
+                .if_layers
+                .iter()
+                .all(|&layer| self.next.get_bit(layer as usize));
+            if active {
+                self.current.set_bit(conditional.then_layer as usize, true);
+            }
+        }
+    }
+
+    /// Process `action` of `key`, which uses up the tapped one-shot
+    /// layers when pressed. They stay on until that same key is
+    /// released, so that releasing other keys held from before does
+    /// not switch them off.
+    fn process_key(&mut self, key: usize, action: &Action, pressed: bool, changed: bool) {
+        if changed && !action.is_modifier() {
+            if pressed && self.one_shot_armed != 0 {
+                self.one_shot_consumed |= self.one_shot_armed;
+                self.one_shot_armed = 0;
+                self.one_shot_key = key;
+            } else if !pressed && key == self.one_shot_key {
+                self.next &= !self.one_shot_consumed;
+                self.one_shot_consumed = 0;
+            }
+        }
+        self.process(action, pressed, changed);
+    }
+
+    /// Make `layer` the default layer, and return whether it was not
+    /// already.
+    fn set_default(&mut self, layer: u8) -> bool {
+        if (layer as usize) >= LAYER_COUNT || layer == self.default {
+            return false;
+        }
+        self.next.set_bit(self.default as usize, false);
+        self.next.set_bit(layer as usize, true);
+        self.default = layer;
+        true
+    }
+
+    /// Restore the default layer saved in `eeprom`, if there is one.
+    fn load(&mut self, eeprom: &Eeprom) {
+        let word = eeprom.read_word(DEFAULT_LAYER);
+        if word & 0xffff_0000 == DEFAULT_LAYER_MAGIC {
+            self.set_default(word as u8);
+            self.update_current();
         }
     }
+
+    fn save(&mut self, eeprom: &mut Eeprom) -> nb::Result<(), EepromError> {
+        if !self.saving {
+            return Ok(());
+        }
+        let result =
+            eeprom.write_word(DEFAULT_LAYER, DEFAULT_LAYER_MAGIC | u32::from(self.default));
+        match result {
+            Err(nb::Error::WouldBlock) => {}
+            // give up rather than retrying forever
+            _ => self.saving = false,
+        }
+        result
+    }
 }
 
 impl EventProcessor for Layers {
     fn process(&mut self, action: &Action, pressed: bool, changed: bool) {
         if changed {
+            if let (Action::SetDefaultLayer(layer), true) = (*action, pressed) {
+                if self.set_default(layer) {
+                    self.saving = true;
+                }
+            }
             match (*action, pressed) {
                 (Action::LayerMomentary(layer), _) => self.next.set_bit(layer as usize, pressed),
                 (Action::LayerToggle(layer), true) => {
@@ -165,40 +594,264 @@ impl EventProcessor for Layers {
                 }
                 (Action::LayerOn(layer), true) => self.next.set_bit(layer as usize, true),
                 (Action::LayerOff(layer), true) => self.next.set_bit(layer as usize, false),
+                (Action::OneShotLayer(layer), true) => {
+                    self.one_shot_held.set_bit(layer as usize, true);
+                    self.one_shot_armed.set_bit(layer as usize, false);
+                    self.next.set_bit(layer as usize, true)
+                }
+                (Action::OneShotLayer(layer), false) => {
+                    self.one_shot_held.set_bit(layer as usize, false);
+                    if self.one_shot_used.get_bit(layer as usize) {
+                        self.one_shot_used.set_bit(layer as usize, false);
+                        self.next.set_bit(layer as usize, false)
+                    } else {
+                        self.one_shot_since = self.now;
+                        self.one_shot_armed.set_bit(layer as usize, true)
+                    }
+                }
+                (_, true) if !action.is_modifier() => {
+                    self.one_shot_used |= self.one_shot_held;
+                    &mut self.next
+                }
                 _ => &mut self.next,
             };
         }
     }
 
     fn finish(&mut self) {
-        self.current = self.next;
+        // nothing switches off the default layer
+        self.next.set_bit(self.default as usize, true);
+        self.update_current();
+    }
+}
+
+/// Modifiers set by tapping `Action::OneShotMod`
+struct OneShotMods {
+    /// Applied to the next key press only
+    armed: u8,
+    /// Applied until the modifier is tapped again
+    locked: u8,
+    /// One-shot modifier keys that are currently held down
+    held: u8,
+    /// Held one-shot modifiers that were used for another key, and
+    /// so act as a plain modifier
+    used: u8,
+    /// A key press consumed the armed modifiers
+    consumed: bool,
+}
+
+impl OneShotMods {
+    const fn new() -> OneShotMods {
+        OneShotMods {
+            armed: 0,
+            locked: 0,
+            held: 0,
+            used: 0,
+            consumed: false,
+        }
+    }
+
+    /// Modifiers to add to the current report
+    fn modifiers(&self) -> u8 {
+        self.armed | self.locked
+    }
+}
+
+impl EventProcessor for OneShotMods {
+    fn process(&mut self, action: &Action, pressed: bool, changed: bool) {
+        if !changed {
+            return;
+        }
+        match (*action, pressed) {
+            (Action::OneShotMod(code), true) if code.is_modifier() => {
+                self.held |= code.modifier_mask()
+            }
+            (Action::OneShotMod(code), false) if code.is_modifier() => {
+                let mask = code.modifier_mask();
+                if self.used & mask == 0 {
+                    // tapped: arm, then lock, then release
+                    if self.locked & mask != 0 {
+                        self.locked &= !mask;
+                    } else if self.armed & mask != 0 {
+                        self.armed &= !mask;
+                        self.locked |= mask;
+                    } else {
+                        self.armed |= mask;
+                    }
+                }
+                self.held &= !mask;
+                self.used &= !mask;
+            }
+            (Action::Key(code), true)
+            | (Action::OneShotMod(code), true)
+            | (Action::KeyWithMods(_, code), true)
+                if code.is_normal_key() =>
+            {
+                self.used |= self.held;
+                self.consumed = true;
+            }
+            _ => {}
+        }
+    }
+
+    fn finish(&mut self) {
+        if self.consumed {
+            self.armed = 0;
+            self.consumed = false;
+        }
+    }
+}
+
+/// Shifts letters typed after `Action::CapsWord`, until a key
+/// other than a letter, digit, `-`, `_` or Backspace is pressed
+struct CapsWord {
+    active: bool,
+    /// The last key pressed is a letter
+    shift: bool,
+}
+
+impl CapsWord {
+    const fn new() -> CapsWord {
+        CapsWord {
+            active: false,
+            shift: false,
+        }
+    }
+
+    /// Modifiers to add to the current report
+    fn modifiers(&self) -> u8 {
+        if self.active && self.shift {
+            KeyCode::LShift.modifier_mask()
+        } else {
+            0
+        }
+    }
+}
+
+impl EventProcessor for CapsWord {
+    fn process(&mut self, action: &Action, pressed: bool, changed: bool) {
+        if !(changed && pressed) {
+            return;
+        }
+        match *action {
+            Action::CapsWord => {
+                self.active = !self.active;
+                self.shift = false;
+            }
+            Action::Key(code) | Action::KeyWithMods(_, code)
+                if self.active && code.is_normal_key() =>
+            {
+                self.shift = code.is_letter();
+                // Shift+Minus gives `_`, so it continues the word too
+                let continues = code.is_letter()
+                    || code.is_digit()
+                    || code == KeyCode::Minus
+                    || code == KeyCode::BSpace;
+                if !continues {
+                    self.active = false;
+                }
+            }
+            _ => {}
+        }
     }
 }
 
 #[derive(Default)]
 struct HidProcessor {
     pub report: HidReport,
+    /// Normal keys that did not fit into `report`
+    pub rollover: NkroReport,
     /// Number of normal keys to be sent in `report`
     i: usize,
+    /// Consumer control usage of the last pressed `Consumer` key
+    pub consumer: u16,
+    /// System control usage of the last pressed `System` key
+    pub system: u8,
 }
 
 impl EventProcessor for HidProcessor {
     fn process(&mut self, action: &Action, pressed: bool, _changed: bool) {
         if pressed {
-            if let Action::Key(code) = *action {
+            if let Action::KeyWithMods(mods, _) = *action {
+                self.report.modifiers |= mods;
+            }
+            if let Action::Consumer(usage) = *action {
+                self.consumer = usage;
+            }
+            if let Action::System(key) = *action {
+                self.system = key as u8;
+            }
+            if let Action::Key(code) | Action::OneShotMod(code) | Action::KeyWithMods(_, code) =
+                *action
+            {
                 if code.is_modifier() {
-                    self.report
-                        .modifiers
-                        .set_bit(code as usize - KeyCode::LCtrl as usize, true);
-                } else if code.is_normal_key() && self.i < self.report.keys.len() {
-                    self.report.keys[self.i] = code as u8;
-                    self.i += 1;
+                    self.report.modifiers |= code.modifier_mask();
+                } else if code.is_normal_key() {
+                    if self.i < self.report.keys.len() {
+                        self.report.keys[self.i] = code as u8;
+                        self.i += 1;
+                    } else {
+                        self.rollover.press(code);
+                    }
                 }
             }
         }
     }
 }
 
+impl EventProcessor for MouseKeys {
+    fn process(&mut self, action: &Action, pressed: bool, _changed: bool) {
+        if pressed {
+            self.press(*action);
+        }
+    }
+}
+
+impl EventProcessor for MacroPlayer {
+    fn process(&mut self, action: &Action, pressed: bool, changed: bool) {
+        if changed && pressed {
+            if let Action::Macro(index) = *action {
+                if let Some(steps) = MACROS.get(index as usize) {
+                    self.play(steps);
+                }
+            }
+        }
+    }
+}
+
+impl EventProcessor for MacroRecorder {
+    fn process(&mut self, action: &Action, pressed: bool, changed: bool) {
+        if !changed {
+            return;
+        }
+        match (*action, pressed) {
+            (Action::DynMacroRecord, true) => self.start(),
+            (Action::DynMacroStop, true) => self.stop(SAVE_RECORDED_MACRO),
+            (Action::Key(code), true) => self.record(MacroStep::Press(code), SAVE_RECORDED_MACRO),
+            (Action::Key(code), false) => {
+                self.record(MacroStep::Release(code), SAVE_RECORDED_MACRO)
+            }
+            (Action::KeyWithMods(mods, code), true) => {
+                for modifier in KeyCode::modifiers(mods) {
+                    self.record(MacroStep::Press(modifier), SAVE_RECORDED_MACRO);
+                }
+                if code != KeyCode::No {
+                    self.record(MacroStep::Press(code), SAVE_RECORDED_MACRO);
+                }
+            }
+            (Action::KeyWithMods(mods, code), false) => {
+                if code != KeyCode::No {
+                    self.record(MacroStep::Release(code), SAVE_RECORDED_MACRO);
+                }
+                for modifier in KeyCode::modifiers(mods) {
+                    self.record(MacroStep::Release(modifier), SAVE_RECORDED_MACRO);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
 impl<BUFFER> EventProcessor for Led<BUFFER>
 where
     BUFFER: Unsize<[u8]>,
@@ -242,3 +895,196 @@ where
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::layout::{LAYER_FN2, LAYER_QWERTY};
+
+    const ONE_SHOT: usize = 67;
+    const HELD: usize = 29;
+    const NEXT: usize = 30;
+    const FN_KEY: usize = 16;
+    const KEY: usize = 17;
+
+    /// Run `action` of `key` through `layers` as a scan would
+    fn scan(layers: &mut Layers, key: usize, action: Action, pressed: bool) {
+        layers.process_key(key, &action, pressed, true);
+        layers.finish();
+    }
+
+    #[test]
+    fn one_shot_layer_lasts_until_its_key_is_released() {
+        let mut layers = Layers::new();
+        let one_shot = Action::OneShotLayer(LAYER_FN2);
+        let key = Action::Key(KeyCode::A);
+        scan(&mut layers, HELD, key, true);
+        scan(&mut layers, ONE_SHOT, one_shot, true);
+        scan(&mut layers, ONE_SHOT, one_shot, false);
+        assert!(layers.current.get_bit(LAYER_FN2 as usize));
+
+        scan(&mut layers, NEXT, key, true);
+        // releasing a key held from before leaves the layer on
+        scan(&mut layers, HELD, key, false);
+        assert!(layers.current.get_bit(LAYER_FN2 as usize));
+        scan(&mut layers, NEXT, key, false);
+        assert!(!layers.current.get_bit(LAYER_FN2 as usize));
+    }
+
+    #[test]
+    fn one_shot_layer_held_acts_as_momentary() {
+        let mut layers = Layers::new();
+        let one_shot = Action::OneShotLayer(LAYER_FN2);
+        let key = Action::Key(KeyCode::A);
+        scan(&mut layers, ONE_SHOT, one_shot, true);
+        scan(&mut layers, NEXT, key, true);
+        scan(&mut layers, NEXT, key, false);
+        assert!(layers.current.get_bit(LAYER_FN2 as usize));
+        scan(&mut layers, ONE_SHOT, one_shot, false);
+        assert_eq!(layers.current, 0b1);
+    }
+
+    /// A keyboard with `FN_KEY` bringing up the FN layer, and `KEY`
+    /// sending A, or B on the FN layer
+    fn keyboard() -> Keyboard {
+        let mut keyboard = Keyboard::new();
+        let fn_layer = LAYER_FN as usize;
+        let keymap = &mut keyboard.keymap;
+        keymap
+            .set(0, FN_KEY, Action::LayerMomentary(LAYER_FN))
+            .unwrap();
+        keymap.set(0, KEY, Action::Key(KeyCode::A)).unwrap();
+        keymap.set(fn_layer, KEY, Action::Key(KeyCode::B)).unwrap();
+        keyboard
+    }
+
+    /// Scan the matrix with `keys` held, running the keys through the
+    /// hardware-independent processors as `Keyboard::process` does.
+    /// Returns the report.
+    fn scan_matrix(keyboard: &mut Keyboard, keys: &[usize]) -> HidReport {
+        let mut matrix = [0; 9];
+        for &key in keys {
+            matrix.set_bit(key, true);
+        }
+        keyboard.now = keyboard.now.wrapping_add(TICK_MS);
+        keyboard.queue_events(&matrix);
+        keyboard.dispatch_events();
+
+        let mut hid = HidProcessor::default();
+        for key in 0..KEY_COUNT {
+            let pressed = keyboard.state.get_bit(key);
+            let changed = keyboard.previous_state.get_bit(key) != pressed;
+            if pressed || changed {
+                let action = keyboard.action(key);
+                hid.process(&action, pressed, changed);
+                keyboard.layers.process_key(key, &action, pressed, changed);
+                if !pressed {
+                    keyboard.resolved.set_bit(key, false);
+                }
+            }
+        }
+        keyboard.layers.finish();
+        keyboard.previous_state = keyboard.state;
+        hid.report
+    }
+
+    fn sends(report: HidReport, code: KeyCode) -> bool {
+        report.keys == [code as u8, 0, 0, 0, 0, 0]
+    }
+
+    #[test]
+    fn held_key_keeps_its_action_when_the_layer_changes() {
+        let mut keyboard = keyboard();
+        assert!(sends(scan_matrix(&mut keyboard, &[KEY]), KeyCode::A));
+        assert!(sends(
+            scan_matrix(&mut keyboard, &[KEY, FN_KEY]),
+            KeyCode::A
+        ));
+        assert!(keyboard.layers.current.get_bit(LAYER_FN as usize));
+
+        assert!(sends(scan_matrix(&mut keyboard, &[FN_KEY]), KeyCode::No));
+        assert!(sends(
+            scan_matrix(&mut keyboard, &[FN_KEY, KEY]),
+            KeyCode::B
+        ));
+    }
+
+    #[test]
+    fn key_released_after_the_layer_changed() {
+        let mut keyboard = keyboard();
+        scan_matrix(&mut keyboard, &[FN_KEY]);
+        assert!(sends(
+            scan_matrix(&mut keyboard, &[FN_KEY, KEY]),
+            KeyCode::B
+        ));
+        assert!(sends(scan_matrix(&mut keyboard, &[KEY]), KeyCode::B));
+        assert_eq!(keyboard.layers.current, 0b1);
+
+        // the release ends the press of B, rather than of A
+        assert!(sends(scan_matrix(&mut keyboard, &[]), KeyCode::No));
+        assert_eq!(keyboard.resolved, [0; 9]);
+        assert!(sends(scan_matrix(&mut keyboard, &[KEY]), KeyCode::A));
+    }
+
+    #[test]
+    fn default_layer_replaces_the_base_layer() {
+        const DEFAULT_KEY: usize = 18;
+        let mut keyboard = keyboard();
+        let (fn_layer, qwerty) = (LAYER_FN as usize, LAYER_QWERTY as usize);
+        let keymap = &mut keyboard.keymap;
+        keymap
+            .set(fn_layer, DEFAULT_KEY, Action::SetDefaultLayer(LAYER_QWERTY))
+            .unwrap();
+        keymap
+            .set(qwerty, FN_KEY, Action::LayerMomentary(LAYER_FN))
+            .unwrap();
+        keymap.set(qwerty, KEY, Action::Key(KeyCode::Q)).unwrap();
+
+        scan_matrix(&mut keyboard, &[FN_KEY]);
+        scan_matrix(&mut keyboard, &[FN_KEY, DEFAULT_KEY]);
+        scan_matrix(&mut keyboard, &[]);
+        assert_eq!(keyboard.layers.current, 1 << qwerty);
+        assert!(keyboard.layers.saving);
+        assert!(sends(scan_matrix(&mut keyboard, &[KEY]), KeyCode::Q));
+        scan_matrix(&mut keyboard, &[]);
+
+        // layers above it still come up on top
+        scan_matrix(&mut keyboard, &[FN_KEY]);
+        let report = scan_matrix(&mut keyboard, &[FN_KEY, KEY]);
+        assert!(sends(report, KeyCode::B));
+        scan_matrix(&mut keyboard, &[KEY]);
+        assert_eq!(keyboard.layers.current, 1 << qwerty);
+
+        // neither the same layer again nor layers that do not exist
+        // are saved
+        let layers = &mut keyboard.layers;
+        layers.saving = false;
+        layers.process(&Action::SetDefaultLayer(LAYER_QWERTY), true, true);
+        layers.finish();
+        assert!(!layers.saving);
+        layers.process(&Action::SetDefaultLayer(9), true, true);
+        layers.finish();
+        assert_eq!(layers.current, 1 << qwerty);
+        assert!(!layers.saving);
+    }
+
+    #[test]
+    fn fn_in_the_bt_layer_brings_up_fn2() {
+        let mut layers = Layers::new();
+        let fn_key = Action::LayerMomentary(LAYER_FN);
+        let bt_key = Action::LayerMomentary(LAYER_BT);
+        let (fn_layer, fn2, bt) = (1 << LAYER_FN, 1 << LAYER_FN2, 1 << LAYER_BT);
+        scan(&mut layers, HELD, fn_key, true);
+        assert_eq!(layers.current, 0b1 | fn_layer);
+
+        scan(&mut layers, NEXT, bt_key, true);
+        assert_eq!(layers.current, 0b1 | fn_layer | bt | fn2);
+        // FN2 is not switched on by itself, only brought up
+        assert_eq!(layers.next & fn2, 0);
+
+        scan(&mut layers, HELD, fn_key, false);
+        assert_eq!(layers.current, 0b1 | bt);
+        scan(&mut layers, NEXT, bt_key, false);
+        assert_eq!(layers.current, 0b1);
+    }
+}
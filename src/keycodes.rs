@@ -1,5 +1,7 @@
 #![allow(dead_code)]
 
+use core::mem::transmute;
+
 // USB HID KeyCodes
 #[derive(PartialOrd, PartialEq, Copy, Clone)]
 pub enum KeyCode {
@@ -125,8 +127,93 @@ impl KeyCode {
     pub fn is_normal_key(self) -> bool {
         self >= KeyCode::A && self <= KeyCode::Application
     }
+
+    pub fn is_letter(self) -> bool {
+        self >= KeyCode::A && self <= KeyCode::Z
+    }
+
+    /// Punctuation keys, from `-` to `/`
+    pub fn is_symbol(self) -> bool {
+        self >= KeyCode::Minus && self <= KeyCode::Slash
+    }
+
+    /// Digits of the number row and the keypad
+    pub fn is_digit(self) -> bool {
+        (self >= KeyCode::N1 && self <= KeyCode::N0)
+            || (self >= KeyCode::Kp1 && self <= KeyCode::Kp0)
+    }
+
+    /// Bit for this modifier in the HID report's modifier byte, 0 for
+    /// other keys
+    pub fn modifier_mask(self) -> u8 {
+        if self.is_modifier() {
+            1 << (self as u8 - KeyCode::LCtrl as u8)
+        } else {
+            0
+        }
+    }
+
+    /// The modifier keys set in the modifier byte `mask`
+    pub fn modifiers(mask: u8) -> impl Iterator<Item = KeyCode> {
+        (0..8)
+            .filter(move |bit| mask & (1 << bit) != 0)
+            .filter_map(|bit| KeyCode::from_u8(KeyCode::LCtrl as u8 + bit))
+    }
+
+    /// The key code with value `code`, if there is one
+    pub fn from_u8(code: u8) -> Option<KeyCode> {
+        let modifier = code >= KeyCode::LCtrl as u8 && code <= KeyCode::RMeta as u8;
+        if code <= KeyCode::Application as u8 || modifier {
+            Some(unsafe { transmute(code) })
+        } else {
+            None
+        }
+    }
+}
+
+/// Usages of the system control collection, for `Action::System`
+#[derive(PartialEq, Copy, Clone)]
+pub enum SystemKey {
+    PowerDown = 0x81,
+    Sleep = 0x82,
+    /// Also wakes up a suspended USB host
+    WakeUp = 0x83,
+}
+
+impl SystemKey {
+    pub fn from_u8(usage: u8) -> Option<SystemKey> {
+        match usage {
+            0x81 => Some(SystemKey::PowerDown),
+            0x82 => Some(SystemKey::Sleep),
+            0x83 => Some(SystemKey::WakeUp),
+            _ => None,
+        }
+    }
 }
 
+// Modifier bits of the HID report, for `Action::KeyWithMods`
+pub const MOD_LCTRL: u8 = 0x01;
+pub const MOD_LSHIFT: u8 = 0x02;
+pub const MOD_LALT: u8 = 0x04;
+pub const MOD_LMETA: u8 = 0x08;
+pub const MOD_RCTRL: u8 = 0x10;
+pub const MOD_RSHIFT: u8 = 0x20;
+pub const MOD_RALT: u8 = 0x40;
+pub const MOD_RMETA: u8 = 0x80;
+pub const MOD_SHIFT: u8 = MOD_LSHIFT | MOD_RSHIFT;
+pub const MEH: u8 = MOD_LCTRL | MOD_LSHIFT | MOD_LALT;
+pub const HYPER: u8 = MEH | MOD_LMETA;
+
+// Usages of the consumer page, for `Action::Consumer`
+pub const BRIGHTNESS_UP: u16 = 0x6F;
+pub const BRIGHTNESS_DOWN: u16 = 0x70;
+pub const NEXT_TRACK: u16 = 0xB5;
+pub const PREV_TRACK: u16 = 0xB6;
+pub const PLAY_PAUSE: u16 = 0xCD;
+pub const MUTE: u16 = 0xE2;
+pub const VOLUME_UP: u16 = 0xE9;
+pub const VOLUME_DOWN: u16 = 0xEA;
+
 /// Index of each physical key in the scan matrix
 #[rustfmt::skip]
 pub enum KeyIndex {
@@ -136,3 +223,22 @@ pub enum KeyIndex {
     LShift,   Z,     X,    C,   V,   B,     N,   M,   Comma, Dot,  Slash,  No2,      No3,      RShift,
     LCtrl,    LMeta, LAlt, No4, No5, Space, No6, No7, No8,   No9,  RAlt,   FN,       Anne,     RCtrl
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn modifier_masks() {
+        let masks = [
+            (KeyCode::LCtrl, MOD_LCTRL),
+            (KeyCode::LShift, MOD_LSHIFT),
+            (KeyCode::RMeta, MOD_RMETA),
+            (KeyCode::A, 0),
+            (KeyCode::No, 0),
+        ];
+        for &(code, mask) in masks.iter() {
+            assert_eq!(code.modifier_mask(), mask);
+        }
+    }
+}
@@ -0,0 +1,173 @@
+use crate::action::Action;
+use crate::codec::{self, CodecError, LAYOUT_BLOB_SIZE};
+use crate::eeprom::{Eeprom, EepromError, KEYMAP};
+use crate::layout::{Layout, LAYERS, LAYER_COUNT};
+
+/// Bytes of the EEPROM kept for each layer, the longest layout blob
+/// rounded up to whole words
+pub const LAYER_SLOT: usize = (LAYOUT_BLOB_SIZE + 3) / 4 * 4;
+
+#[derive(Debug)]
+pub enum KeymapError {
+    /// The layer or key does not exist, or the action refers to a
+    /// layer or Bluetooth host that does not
+    OutOfRange,
+    Codec(CodecError),
+    Eeprom(EepromError),
+}
+
+/// The layers the keyboard runs on, starting out as
+/// [`layout::LAYERS`] and changeable at runtime.
+///
+/// In the EEPROM, each layer is a layout blob, see [`codec`], in a
+/// slot of `LAYER_SLOT` bytes. Like [`macros::MacroRecorder`] the
+/// layers are saved one word per call to `save`.
+pub struct Keymap {
+    layers: [Layout; LAYER_COUNT],
+    /// Bit-field of the layers changed since they were last saved
+    unsaved: u8,
+    /// Layer being saved and the position of the next word, if
+    /// saving. The magic is cleared first and written last, so that
+    /// an interrupted save leaves the compiled-in layer rather than a
+    /// broken one.
+    saving: Option<(usize, usize)>,
+    /// Blob of the layer being saved, and its length in words
+    blob: [u8; LAYER_SLOT],
+    blob_words: usize,
+}
+
+impl Keymap {
+    pub const fn new() -> Keymap {
+        Keymap {
+            layers: LAYERS,
+            unsaved: 0,
+            saving: None,
+            blob: [0; LAYER_SLOT],
+            blob_words: 0,
+        }
+    }
+
+    pub fn layer(&self, layer: usize) -> &Layout {
+        &self.layers[layer]
+    }
+
+    /// The action of `key` in `layer`, if there is such a key
+    #[allow(dead_code)]
+    pub fn get(&self, layer: usize, key: usize) -> Option<Action> {
+        self.layers.get(layer)?.get(key).copied()
+    }
+
+    /// Map `key` in `layer` to `action`, and save the keymap.
+    #[allow(dead_code)]
+    pub fn set(&mut self, layer: usize, key: usize, action: Action) -> Result<(), KeymapError> {
+        let entry = self
+            .layers
+            .get_mut(layer)
+            .and_then(|layer| layer.get_mut(key))
+            .ok_or(KeymapError::OutOfRange)?;
+        if !action.is_valid() {
+            return Err(KeymapError::OutOfRange);
+        }
+        if *entry != action {
+            *entry = action;
+            self.unsaved |= 1 << layer;
+        }
+        Ok(())
+    }
+
+    /// Replace all of `layer`, and save the keymap.
+    #[allow(dead_code)]
+    pub fn set_layer(&mut self, layer: usize, actions: &Layout) -> Result<(), KeymapError> {
+        let entry = self.layers.get_mut(layer).ok_or(KeymapError::OutOfRange)?;
+        if !actions.iter().all(|action| action.is_valid()) {
+            return Err(KeymapError::OutOfRange);
+        }
+        *entry = *actions;
+        self.unsaved |= 1 << layer;
+        Ok(())
+    }
+
+    /// Go back to the compiled-in layers, and save them.
+    #[allow(dead_code)]
+    pub fn reset(&mut self) {
+        self.layers = LAYERS;
+        self.unsaved = (1 << LAYER_COUNT) - 1;
+    }
+
+    /// Restore the layers saved in `eeprom`, keeping the compiled-in
+    /// ones for each layer that is missing or does not check out.
+    pub fn load(&mut self, eeprom: &Eeprom) {
+        let mut blob = [0; LAYER_SLOT];
+        for (i, layer) in self.layers.iter_mut().enumerate() {
+            for (j, bytes) in blob.chunks_mut(4).enumerate() {
+                let word = eeprom.read_word(KEYMAP + i * LAYER_SLOT + 4 * j);
+                bytes.copy_from_slice(&word.to_le_bytes());
+            }
+            if let Ok((index, decoded)) = codec::decode_layout(&blob) {
+                if index as usize == i {
+                    *layer = decoded;
+                }
+            }
+        }
+    }
+
+    /// The layout blob of `layer` for export, see [`codec`]
+    #[allow(dead_code)]
+    pub fn export(&self, layer: usize, buffer: &mut [u8]) -> Result<usize, KeymapError> {
+        let layout = self.layers.get(layer).ok_or(KeymapError::OutOfRange)?;
+        codec::encode_layout(layer as u8, layout, buffer).map_err(KeymapError::Codec)
+    }
+
+    /// Take over an uploaded layout blob, and save it.
+    #[allow(dead_code)]
+    pub fn import(&mut self, blob: &[u8]) -> Result<(), KeymapError> {
+        let (layer, layout) = codec::decode_layout(blob).map_err(KeymapError::Codec)?;
+        self.set_layer(layer as usize, &layout)
+    }
+
+    /// Write the next word of a pending save to `eeprom`.
+    pub fn save(&mut self, eeprom: &mut Eeprom) -> nb::Result<(), KeymapError> {
+        if self.saving.is_none() && self.unsaved != 0 {
+            let layer = self.unsaved.trailing_zeros() as usize;
+            self.unsaved &= !(1 << layer);
+            self.blob = [0; LAYER_SLOT];
+            let size = codec::encode_layout(layer as u8, &self.layers[layer], &mut self.blob)
+                .map_err(|e| nb::Error::Other(KeymapError::Codec(e)))?;
+            self.blob_words = (size + 3) / 4;
+            self.saving = Some((layer, 0));
+        }
+        let (layer, position) = match self.saving {
+            Some(saving) => saving,
+            None => return Ok(()),
+        };
+        // the first word, holding the magic, is cleared first and
+        // written last
+        let (word, value) = match position {
+            0 => (0, 0),
+            position if position < self.blob_words => (position, self.blob_word(position)),
+            _ => (0, self.blob_word(0)),
+        };
+
+        let result = eeprom.write_word(KEYMAP + layer * LAYER_SLOT + 4 * word, value);
+        match result {
+            Err(nb::Error::WouldBlock) => {}
+            // give up on this save rather than retrying the word
+            // forever, and start the layer over from its first word
+            Err(_) => {
+                self.saving = None;
+                self.unsaved |= 1 << layer;
+            }
+            Ok(()) if position >= self.blob_words => self.saving = None,
+            Ok(()) => self.saving = Some((layer, position + 1)),
+        }
+        result.map_err(|e| match e {
+            nb::Error::WouldBlock => nb::Error::WouldBlock,
+            nb::Error::Other(e) => nb::Error::Other(KeymapError::Eeprom(e)),
+        })
+    }
+
+    fn blob_word(&self, index: usize) -> u32 {
+        let bytes = &self.blob[4 * index..4 * index + 4];
+        u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+    }
+}
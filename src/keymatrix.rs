@@ -1,3 +1,7 @@
+use crate::clock::TICK_MS;
+use crate::debounce::Debouncer;
+use crate::ghosting::GhostFilter;
+use crate::layout::DEBOUNCE;
 use bit_field::BitArray;
 use embedded_hal::digital::{InputPin, OutputPin};
 use hal::gpio::gpioa::*;
@@ -39,8 +43,12 @@ type ColumnPins = (
 pub type KeyState = [u8; (ROWS * COLUMNS + 2) / 8]; // [u8; 9]
 
 pub struct KeyMatrix {
-    /// Stores the currently pressed down keys from last sample.
+    /// Stores the currently pressed down keys, debounced and without
+    /// ghost keys.
     pub state: KeyState,
+    debounced: KeyState,
+    debouncer: Debouncer,
+    pub ghost_filter: GhostFilter,
     row_pins: RowPins,
     column_pins: ColumnPins,
 }
@@ -49,12 +57,16 @@ impl KeyMatrix {
     pub fn new(row_pins: RowPins, column_pins: ColumnPins) -> Self {
         Self {
             state: [0; 9],
+            debounced: [0; 9],
+            debouncer: Debouncer::new(&DEBOUNCE),
+            ghost_filter: GhostFilter::new(),
             row_pins,
             column_pins,
         }
     }
 
     pub fn sample(&mut self, syst: &SYST) {
+        let mut raw: KeyState = [0; 9];
         for column in 0..COLUMNS {
             self.enable_column(column);
 
@@ -64,18 +76,17 @@ impl KeyMatrix {
             let wait_until_tick = current_tick - 100;
             while syst.cvr.read() > wait_until_tick {}
 
-            self.state.set_bit(column, self.row_pins.0.is_high());
-            self.state
-                .set_bit(column + COLUMNS, self.row_pins.1.is_high());
-            self.state
-                .set_bit(column + 2 * COLUMNS, self.row_pins.2.is_high());
-            self.state
-                .set_bit(column + 3 * COLUMNS, self.row_pins.3.is_high());
-            self.state
-                .set_bit(column + 4 * COLUMNS, self.row_pins.4.is_high());
+            raw.set_bit(column, self.row_pins.0.is_high());
+            raw.set_bit(column + COLUMNS, self.row_pins.1.is_high());
+            raw.set_bit(column + 2 * COLUMNS, self.row_pins.2.is_high());
+            raw.set_bit(column + 3 * COLUMNS, self.row_pins.3.is_high());
+            raw.set_bit(column + 4 * COLUMNS, self.row_pins.4.is_high());
 
             self.disable_column(column);
         }
+        self.debouncer
+            .update(&raw, &mut self.debounced, TICK_MS as u8);
+        self.ghost_filter.filter(&self.debounced, &mut self.state);
     }
 
     fn enable_column(&mut self, column: usize) {
@@ -1,7 +1,20 @@
 use crate::action::Action;
 use crate::action::Action::*;
+use crate::combo::{Combo, ComboTrigger};
+use crate::debounce::{DebounceConfig, DebounceMode};
+use crate::keyboard::ConditionalLayer;
 use crate::keycodes::KeyCode::*;
+use crate::keycodes::SystemKey::{PowerDown, Sleep, WakeUp};
+use crate::keycodes::{
+    KeyIndex, BRIGHTNESS_DOWN, BRIGHTNESS_UP, HYPER, MEH, MOD_SHIFT, MUTE, NEXT_TRACK, PLAY_PAUSE,
+    PREV_TRACK, VOLUME_DOWN, VOLUME_UP,
+};
 use crate::keymatrix::{COLUMNS, ROWS};
+use crate::leader::LeaderSequence;
+use crate::macros::{Macro, MacroStep};
+use crate::mouse::{AccelerationCurve, MouseConfig};
+use crate::overrides::KeyOverride;
+use crate::tapping::{AutoShiftConfig, HoldMode, TapDance, TappingConfig};
 
 /*
   ,-----------------------------------------------------------------------------.
@@ -19,14 +32,172 @@ use crate::keymatrix::{COLUMNS, ROWS};
 
 pub type Layout = [Action; COLUMNS * ROWS];
 
-pub const LAYERS: [Layout; 4] = [BASE, FN, FN2, BT];
+/// Base layers come first, so that the other layers cover them
+pub const LAYER_COUNT: usize = 6;
+pub const LAYERS: [Layout; LAYER_COUNT] = [BASE, QWERTY, COLEMAK, FN, FN2, BT];
 
-pub const LAYER_FN: u8 = 1;
-pub const LAYER_BT: u8 = 3;
+pub const LAYER_BASE: u8 = 0;
+pub const LAYER_QWERTY: u8 = 1;
+pub const LAYER_COLEMAK: u8 = 2;
+pub const LAYER_FN: u8 = 3;
+pub const LAYER_FN2: u8 = 4;
+pub const LAYER_BT: u8 = 5;
+
+/// Holding FN while in the BT layer brings up FN2
+pub const CONDITIONAL_LAYERS: [ConditionalLayer; 1] = [ConditionalLayer {
+    if_layers: [LAYER_FN, LAYER_BT],
+    then_layer: LAYER_FN2,
+}];
+
+pub const TAPPING: TappingConfig = TappingConfig {
+    term: 200,
+    mode: HoldMode::PermissiveHold,
+    one_shot_timeout: 3000,
+};
+
+pub const DEBOUNCE: DebounceConfig = DebounceConfig {
+    mode: DebounceMode::EagerPressDeferRelease,
+    time: 5,
+};
+
+pub const AUTO_SHIFT: AutoShiftConfig = AutoShiftConfig {
+    enabled: false,
+    timeout: 175,
+    letters: true,
+    digits: true,
+    symbols: true,
+};
+
+pub const TAP_DANCES: [TapDance; 1] = [
+    // Anne key: tap for leader sequences, hold for the BT layer,
+    // double tap to lock it
+    TapDance {
+        tap: &[Leader, LayerToggle(LAYER_BT)],
+        hold: &[BT_M],
+    },
+];
+
+pub const COMBOS: [Combo; 1] = [
+    // both Shifts
+    Combo {
+        keys: &[KeyIndex::LShift as u8, KeyIndex::RShift as u8],
+        action: CapsWord,
+        timeout: 50,
+        trigger: ComboTrigger::OnPress,
+    },
+];
+
+pub const KEY_OVERRIDES: [KeyOverride; 1] = [
+    // Shift+Backspace
+    KeyOverride {
+        mods: MOD_SHIFT,
+        key: BSpace,
+        replacement: Delete,
+    },
+];
+
+/// Milliseconds to wait for the next key of a leader sequence
+pub const LEADER_TIMEOUT: u32 = 1000;
+
+#[rustfmt::skip]
+pub const LEADER_SEQUENCES: [LeaderSequence; 19] = [
+    LeaderSequence { keys: &[B, N1], action: BtConnectHost(1) },
+    LeaderSequence { keys: &[B, N2], action: BtConnectHost(2) },
+    LeaderSequence { keys: &[B, N3], action: BtConnectHost(3) },
+    LeaderSequence { keys: &[B, N4], action: BtConnectHost(4) },
+    LeaderSequence { keys: &[B, S, N1], action: BtSaveHost(1) },
+    LeaderSequence { keys: &[B, S, N2], action: BtSaveHost(2) },
+    LeaderSequence { keys: &[B, S, N3], action: BtSaveHost(3) },
+    LeaderSequence { keys: &[B, S, N4], action: BtSaveHost(4) },
+    LeaderSequence { keys: &[B, D, N1], action: BtDeleteHost(1) },
+    LeaderSequence { keys: &[B, D, N2], action: BtDeleteHost(2) },
+    LeaderSequence { keys: &[B, D, N3], action: BtDeleteHost(3) },
+    LeaderSequence { keys: &[B, D, N4], action: BtDeleteHost(4) },
+    LeaderSequence { keys: &[B, O], action: BtOn },
+    LeaderSequence { keys: &[B, F], action: BtOff },
+    LeaderSequence { keys: &[B, B], action: BtBroadcast },
+    LeaderSequence { keys: &[B, L], action: BtToggleLegacyMode },
+    LeaderSequence { keys: &[L, O], action: LedOff },
+    LeaderSequence { keys: &[L, N], action: LedOn },
+    LeaderSequence { keys: &[U], action: UsbToggle },
+];
+
+pub const MACROS: [Macro; 2] = [
+    // duplicate the selection
+    &[
+        MacroStep::Press(LCtrl),
+        MacroStep::Tap(C),
+        MacroStep::Tap(V),
+        MacroStep::Tap(V),
+        MacroStep::Release(LCtrl),
+    ],
+    // ->, with the keycodes of a US layout on the host
+    &[
+        MacroStep::Tap(Minus),
+        MacroStep::Press(LShift),
+        MacroStep::Tap(Dot),
+        MacroStep::Release(LShift),
+    ],
+];
+
+/// Keep the macro recorded with `DynMacroRecord` across resets, in
+/// the data EEPROM
+pub const SAVE_RECORDED_MACRO: bool = true;
+
+/// Milliseconds the settings must stay unchanged before they are
+/// saved, to spare the EEPROM while flipping through themes
+pub const SETTINGS_SAVE_DELAY: u32 = 5000;
+
+pub const MOUSE_KEYS: MouseConfig = MouseConfig {
+    interval: 15,
+    speed: 2,
+    max_speed: 24,
+    delay: 150,
+    time_to_max: 1500,
+    curve: AccelerationCurve::Quadratic,
+    wheel_interval: 90,
+};
+
+/// Send an N-key rollover report over USB when the host allows it,
+/// toggled with `NkroToggle`
+pub const NKRO: bool = true;
 
 // activate by indexing into LAYERS
 const FN_M: Action = LayerMomentary(LAYER_FN);
 const BT_M: Action = LayerMomentary(LAYER_BT);
+const BT_TD: Action = TapDance(0);
+const FN2_OS: Action = OneShotLayer(LAYER_FN2);
+const DUP: Action = Macro(0);
+const ARROW: Action = Macro(1);
+const REC: Action = DynMacroRecord;
+const STOP: Action = DynMacroStop;
+const PLAY: Action = DynMacroPlay;
+const AS_T: Action = AutoShiftToggle;
+const HYPR: Action = KeyWithMods(HYPER, No);
+const MEH_: Action = KeyWithMods(MEH, No);
+const MPLY: Action = Consumer(PLAY_PAUSE);
+const MNXT: Action = Consumer(NEXT_TRACK);
+const MPRV: Action = Consumer(PREV_TRACK);
+const MUTE_: Action = Consumer(MUTE);
+const VOLU: Action = Consumer(VOLUME_UP);
+const VOLD: Action = Consumer(VOLUME_DOWN);
+const BRIU: Action = Consumer(BRIGHTNESS_UP);
+const BRID: Action = Consumer(BRIGHTNESS_DOWN);
+const PWR: Action = System(PowerDown);
+const SLEP: Action = System(Sleep);
+const WAKE: Action = System(WakeUp);
+const MS_U: Action = MouseUp;
+const MS_D: Action = MouseDown;
+const MS_L: Action = MouseLeft;
+const MS_R: Action = MouseRight;
+const WH_U: Action = MouseWheelUp;
+const WH_D: Action = MouseWheelDown;
+const BTN1: Action = MouseButton(1);
+const BTN2: Action = MouseButton(2);
+const BTN3: Action = MouseButton(4);
+const DF_DV: Action = SetDefaultLayer(LAYER_BASE);
+const DF_QW: Action = SetDefaultLayer(LAYER_QWERTY);
+const DF_CM: Action = SetDefaultLayer(LAYER_COLEMAK);
 const __: Action = Transparent;
 const LED_NT: Action = LedNextTheme;
 const LED_NB: Action = LedNextBrightness;
@@ -38,28 +209,46 @@ Escape  N1 N2  N3    N4    N5    N6    N7    N8    N9    N0   LBracket   RBracke
   Tab  Quote     Comma     Dot     P     Y     F     G     C     R     L    Slash   Equal   BSlash
  LCtrl A     O     E     U     I     D     H     T     N    S   Minus           No Enter
  LShift        SColon     Q     J     K     X     B     M    W   V   Z     No No RShift
-  FN_M   LMeta   LAlt    No No       Space   No No No No   RMeta    FN_M      BT_M   Grave
+  FN_M   LMeta   LAlt    No No       Space   No No No No   RMeta    FN2_OS    BT_TD  Grave
+];
+
+#[rustfmt::skip]
+pub const QWERTY: Layout = layout![
+Escape  N1 N2  N3    N4    N5    N6    N7    N8    N9    N0   Minus   Equal          BSpace
+  Tab  Q     W     E     R     T     Y     U     I     O     P    LBracket   RBracket   BSlash
+ LCtrl  A     S     D     F     G     H     J     K     L    SColon   Quote           No Enter
+ LShift        Z     X     C     V     B     N     M    Comma   Dot   Slash     No No  RShift
+  FN_M   LMeta   LAlt    No No        Space   No No No No   RMeta    FN2_OS    BT_TD  Grave
+];
+
+#[rustfmt::skip]
+pub const COLEMAK: Layout = layout![
+Escape  N1 N2  N3    N4    N5    N6    N7    N8    N9    N0   Minus   Equal          BSpace
+  Tab  Q     W     F     P     G     J     L     U     Y    SColon    LBracket   RBracket   BSlash
+ LCtrl  A     R     S     T     D     H     N     E     I    O   Quote           No Enter
+ LShift        Z     X     C     V     B     K     M    Comma   Dot   Slash     No No  RShift
+  FN_M   LMeta   LAlt    No No        Space   No No No No   RMeta    FN2_OS    BT_TD  Grave
 ];
 
 pub const FN: Layout = layout![
   Grave   F1    F2    F3    F4    F5    F6    F7    F8    F9         F10   F11   F12  Delete
-  __ PgUp  Numlock Kp8   KpPlus  LED_NB LED_NAS  LED_NT   Up  LedToggle __   __   __  PScreen
-  __     Home   Kp4  Kp2  Kp6   Insert     Home   Left  Down      Right  End   __     No __
-  __    PgDown  KpSlash  KpAsterisk KpMinus KpDot   BT_ON    __     __     __ __       No No __
+  __ PgUp  Numlock Kp8   KpPlus  LED_NB LED_NAS  LED_NT   Up  LedToggle MUTE_ VOLD VOLU PScreen
+  CapsWord Home   Kp4  Kp2  Kp6   Insert     Home   Left  Down      Right  End   BRIU   No __
+  __    PgDown  KpSlash  KpAsterisk KpMinus KpDot   BT_ON    MPRV   MPLY   MNXT BRID     No No __
   __  __      __      No No        Reset      No No No No   __          __       __     __
 ];
 
 pub const FN2: Layout = layout![
-    LedOff LedOn LED_NT LED_NAS LED_NB __ __ __ __ __ __ __ __ __
-    __     __    __     __      __     __ __ __ __ __ __ __ __ __
-    __     __    __     __      __     __ __ __ __ __ __ __ No __
-    __     __    __     __      __     __ __ __ __ __ __ __ __ __
-    __     __    __     No      No     __ No No No No __ __ __ __
+    LedOff LedOn LED_NT LED_NAS LED_NB __ __ __ __ __ __ PWR SLEP WAKE
+    __     DUP   ARROW  __      REC    STOP PLAY WH_U MS_U WH_D __ __ __ __
+    __     AS_T  __     __      __     __ BTN1 MS_L MS_D MS_R BTN2 BTN3 No __
+    __     DF_DV DF_QW  DF_CM   __     __ __ __ __ __ __ __ __ __
+    __     HYPR  MEH_   No      No     __ No No No No __ __ __ __
 ];
 
 #[rustfmt::skip]
 pub const BT: Layout = layout![
-    LayerOff(LAYER_BT) BtConnectHost(1) BtConnectHost(2) BtConnectHost(3) BtConnectHost(4) UsbToggle __ __ __ __ BtToggleLegacyMode BtOff BtBroadcast BtOn
+    LayerOff(LAYER_BT) BtConnectHost(1) BtConnectHost(2) BtConnectHost(3) BtConnectHost(4) UsbToggle NkroToggle __ __ __ BtToggleLegacyMode BtOff BtBroadcast BtOn
     BtHostListQuery BtSaveHost(1) BtSaveHost(2) BtSaveHost(3) BtSaveHost(4) __ __ __ __ __ __ __ __ __
     __ BtDeleteHost(1) BtDeleteHost(2) BtDeleteHost(3) BtDeleteHost(4) __ __ __ __ __ __ __ No __
     __ __ __ __ __ LayerToggle(LAYER_BT) LayerOff(LAYER_BT) __ __ __ __ __ __ __
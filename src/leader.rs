@@ -0,0 +1,201 @@
+use crate::action::Action;
+use crate::event::elapsed;
+use crate::keycodes::KeyCode;
+
+/// Longest sequence that can follow the leader key
+pub const MAX_SEQUENCE: usize = 4;
+
+/// Keys typed after `Action::Leader`, standing in for `action`
+pub struct LeaderSequence {
+    pub keys: &'static [KeyCode],
+    pub action: Action,
+}
+
+/// Records the keys typed after `Action::Leader` and matches them
+/// against the sequences in [`layout::LEADER_SEQUENCES`].
+///
+/// Keys are captured at the head of the event queue, once it is
+/// known what they resolve to. A captured key resolves to `Nop`, or
+/// to the action of the sequence it completes.
+pub struct Leader {
+    active: bool,
+    keys: [KeyCode; MAX_SEQUENCE],
+    len: usize,
+    /// When the leader key or the last captured key was pressed
+    since: u32,
+}
+
+impl Leader {
+    pub const fn new() -> Leader {
+        Leader {
+            active: false,
+            keys: [KeyCode::No; MAX_SEQUENCE],
+            len: 0,
+            since: 0,
+        }
+    }
+
+    /// Look at a key pressed with `action`. Returns what the key
+    /// stands for instead, if it was captured.
+    ///
+    /// Modifiers and layer keys are not captured, so they can be used
+    /// to reach the keys of a sequence.
+    pub fn capture(
+        &mut self,
+        action: Action,
+        sequences: &[LeaderSequence],
+        now: u32,
+    ) -> Option<Action> {
+        let code = match action {
+            Action::Leader => {
+                self.active = true;
+                self.len = 0;
+                self.since = now;
+                return None;
+            }
+            Action::Key(code) if self.active && code.is_normal_key() => code,
+            _ => return None,
+        };
+
+        self.keys[self.len] = code;
+        self.len += 1;
+        self.since = now;
+
+        let recorded = &self.keys[..self.len];
+        let exact = sequences.iter().find(|s| s.keys == recorded);
+        let longer = sequences
+            .iter()
+            .any(|s| s.keys.len() > self.len && s.keys.starts_with(recorded));
+
+        if longer && self.len < MAX_SEQUENCE {
+            return Some(Action::Nop);
+        }
+        self.active = false;
+        Some(exact.map_or(Action::Nop, |s| s.action))
+    }
+
+    /// End a sequence nobody typed on for `timeout` milliseconds.
+    /// Returns the action of the sequence recorded so far, if any.
+    pub fn expire(
+        &mut self,
+        sequences: &[LeaderSequence],
+        timeout: u32,
+        now: u32,
+    ) -> Option<Action> {
+        if !self.active || elapsed(now, self.since) < timeout {
+            return None;
+        }
+        self.active = false;
+        let recorded = &self.keys[..self.len];
+        sequences
+            .iter()
+            .find(|s| s.keys == recorded)
+            .map(|s| s.action)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use KeyCode::*;
+
+    const TIMEOUT: u32 = 1000;
+    const SEQUENCES: [LeaderSequence; 4] = [
+        LeaderSequence {
+            keys: &[A],
+            action: Action::CapsWord,
+        },
+        LeaderSequence {
+            keys: &[A, B],
+            action: Action::LedOn,
+        },
+        LeaderSequence {
+            keys: &[C, D, E, F],
+            action: Action::LedOff,
+        },
+        // longer than `MAX_SEQUENCE`, so it can never be typed
+        LeaderSequence {
+            keys: &[C, D, E, F, G],
+            action: Action::Reset,
+        },
+    ];
+
+    /// Press the leader key and then `keys` at the given milliseconds,
+    /// returning what the last key stands for
+    fn script(leader: &mut Leader, keys: &[(u32, KeyCode)]) -> Option<Action> {
+        assert!(leader.capture(Action::Leader, &SEQUENCES, 0).is_none());
+        let mut captured = None;
+        for &(time, code) in keys {
+            captured = leader.capture(Action::Key(code), &SEQUENCES, time);
+        }
+        captured
+    }
+
+    #[test]
+    fn captures_only_after_the_leader() {
+        let mut leader = Leader::new();
+        let key = Action::Key(A);
+        assert!(leader.capture(key, &SEQUENCES, 0).is_none());
+        assert!(leader.expire(&SEQUENCES, TIMEOUT, 5000).is_none());
+    }
+
+    #[test]
+    fn completes_a_sequence() {
+        let mut leader = Leader::new();
+        assert!(script(&mut leader, &[(10, C), (20, D), (30, E)]) == Some(Action::Nop));
+        let last = leader.capture(Action::Key(F), &SEQUENCES, 40);
+        assert!(last == Some(Action::LedOff));
+        // and lets go of the keys after it
+        assert!(leader.capture(Action::Key(A), &SEQUENCES, 50).is_none());
+    }
+
+    #[test]
+    fn modifiers_are_not_captured() {
+        let mut leader = Leader::new();
+        assert!(script(&mut leader, &[(10, A)]) == Some(Action::Nop));
+        assert!(leader
+            .capture(Action::Key(LShift), &SEQUENCES, 20)
+            .is_none());
+        let layer = Action::LayerMomentary(1);
+        assert!(leader.capture(layer, &SEQUENCES, 30).is_none());
+        let last = leader.capture(Action::Key(B), &SEQUENCES, 40);
+        assert!(last == Some(Action::LedOn));
+    }
+
+    #[test]
+    fn unknown_sequences_end_with_nothing() {
+        let mut leader = Leader::new();
+        assert!(script(&mut leader, &[(10, C), (20, A)]) == Some(Action::Nop));
+        assert!(leader.capture(Action::Key(D), &SEQUENCES, 30).is_none());
+    }
+
+    #[test]
+    fn expires_with_the_sequence_so_far() {
+        let mut leader = Leader::new();
+        // A could still go on to A B
+        assert!(script(&mut leader, &[(10, A)]) == Some(Action::Nop));
+        assert!(leader.expire(&SEQUENCES, TIMEOUT, 1009).is_none());
+        let expired = leader.expire(&SEQUENCES, TIMEOUT, 1010);
+        assert!(expired == Some(Action::CapsWord));
+        assert!(leader.expire(&SEQUENCES, TIMEOUT, 2000).is_none());
+
+        // each key restarts the timeout, and unknown prefixes expire
+        // with nothing
+        assert!(script(&mut leader, &[(500, C), (1400, D)]) == Some(Action::Nop));
+        assert!(leader.expire(&SEQUENCES, TIMEOUT, 2399).is_none());
+        assert!(leader.expire(&SEQUENCES, TIMEOUT, 2400).is_none());
+        assert!(leader.capture(Action::Key(E), &SEQUENCES, 2500).is_none());
+    }
+
+    #[test]
+    fn stops_at_the_longest_sequence() {
+        let mut leader = Leader::new();
+        let keys = [(10, C), (20, D), (30, E), (40, F)];
+        assert!(script(&mut leader, &keys) == Some(Action::LedOff));
+        assert!(leader.capture(Action::Key(G), &SEQUENCES, 50).is_none());
+
+        // the leader key starts over
+        let last = script(&mut leader, &[(60, A), (70, B)]);
+        assert!(last == Some(Action::LedOn));
+    }
+}
@@ -1,4 +1,5 @@
 use crate::bluetooth::BluetoothMode;
+use crate::debug::UnwrapLog;
 use crate::keycodes::KeyIndex;
 use crate::keymatrix::KeyState;
 use crate::protocol::{LedOp, Message, MsgType};
@@ -20,11 +21,29 @@ pub enum LedMode {
     Flash,
 }
 
+/// Theme off, as sent by `Led::toggle`
+const THEME_OFF: u8 = 15;
+/// Most `ConfigCmd` steps `Led::restore` takes, in case the LED chip
+/// never reaches the wanted brightness and speed
+const RESTORE_STEPS: u8 = 16;
+
+/// Lighting settings as reported in `LedOp::AckConfigCmd`
+#[derive(Copy, Clone, PartialEq)]
+pub struct LedConfig {
+    pub theme: u8,
+    pub brightness: u8,
+    pub animation_speed: u8,
+}
+
 pub struct Led<BUFFER: 'static + Unsize<[u8]>> {
     pub serial: Serial<LedUsart, BUFFER>,
     pub rx_transfer: Option<Transfer<BUFFER>>,
     pub pc15: PC15<Output>,
     pub state: bool,
+    /// Last lighting settings reported or restored, if any
+    pub config: Option<LedConfig>,
+    /// Settings `restore` is stepping towards, and the steps left
+    restoring: Option<(LedConfig, u8)>,
 }
 
 impl<BUFFER> Led<BUFFER>
@@ -42,6 +61,8 @@ where
             rx_transfer: Some(rx_transfer),
             pc15: pc15.into_output().pull_up(),
             state: false,
+            config: None,
+            restoring: None,
         }
     }
 
@@ -75,10 +96,63 @@ where
         if self.state {
             self.theme_mode()
         } else {
-            self.set_theme(15)
+            self.set_theme(THEME_OFF)
+        }
+    }
+
+    /// Bring back the lighting saved in `Settings`. The LED chip only
+    /// steps through brightness and animation speed, so these are
+    /// stepped until its acknowledgements report the saved values.
+    pub fn restore(&mut self, on: bool, config: Option<LedConfig>) -> nb::Result<(), !> {
+        let config = match config {
+            Some(config) => config,
+            None if on => return self.theme_mode(),
+            None => {
+                self.state = false;
+                return self.set_theme(THEME_OFF);
+            }
+        };
+        self.config = Some(config);
+        self.restoring = Some((config, RESTORE_STEPS));
+        self.state = on;
+        self.set_theme(if on { config.theme } else { THEME_OFF })
+    }
+
+    /// Ask for the current settings once the theme set by `restore`
+    /// is acknowledged, as the chip takes one command at a time.
+    fn start_restore(&mut self) -> nb::Result<(), !> {
+        match self.restoring {
+            Some((target, RESTORE_STEPS)) => {
+                self.restoring = Some((target, RESTORE_STEPS - 1));
+                // no steps, only reports the current settings
+                self.serial
+                    .send(MsgType::Led, LedOp::ConfigCmd as u8, &[0, 0, 0])
+            }
+            _ => Ok(()),
         }
     }
 
+    /// Take the next step of `restore` after the LED chip reported
+    /// `config`.
+    fn step_restore(&mut self, config: LedConfig) -> nb::Result<(), !> {
+        let (target, steps) = match self.restoring {
+            Some(restoring) => restoring,
+            None => return Ok(()),
+        };
+        let speed = config.animation_speed != target.animation_speed;
+        let brightness = config.brightness != target.brightness;
+        if steps == 0 || !(speed || brightness) {
+            self.restoring = None;
+            return Ok(());
+        }
+        self.restoring = Some((target, steps - 1));
+        self.serial.send(
+            MsgType::Led,
+            LedOp::ConfigCmd as u8,
+            &[0, speed as u8, brightness as u8],
+        )
+    }
+
     // next_* cycles through themes/brightness/speed
     pub fn next_theme(&mut self) -> nb::Result<(), !> {
         self.serial
@@ -137,6 +211,7 @@ where
             connected_host,
             mode,
             keyboard_send_usb_report,
+            false,
         )
         .fill_payload(&mut buffer);
         self.set_keys(&buffer[..payload_length])
@@ -169,10 +244,25 @@ where
                     LedOp::AckThemeMode => {
                         // data: [theme id]
                         //crate::heprintln!("Led AckThemeMode {:?}", message.data).ok();
+                        if let (Some(config), Some(&theme), true) =
+                            (self.config.as_mut(), message.data.get(0), self.state)
+                        {
+                            config.theme = theme;
+                        }
+                        self.start_restore().log_error();
                     }
                     LedOp::AckConfigCmd => {
                         // data: [theme id, brightness, animation speed]
                         //crate::heprintln!("Led AckConfigCmd {:?}", message.data).ok();
+                        if let [theme, brightness, animation_speed] = *message.data {
+                            let config = LedConfig {
+                                theme,
+                                brightness,
+                                animation_speed,
+                            };
+                            self.config = Some(config);
+                            self.step_restore(config).log_error();
+                        }
                     }
                     LedOp::AckSetIndividualKeys => {
                         // data: [202]
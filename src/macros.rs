@@ -0,0 +1,298 @@
+use crate::eeprom::{Eeprom, EepromError, RECORDED_MACRO};
+use crate::event::elapsed;
+use crate::hidreport::HidReport;
+use crate::keycodes::KeyCode;
+
+/// One step of a macro. Each press or release is sent in a report of
+/// its own.
+#[allow(dead_code)]
+#[derive(Copy, Clone, PartialEq)]
+pub enum MacroStep {
+    /// Press a key or modifier, and keep it down
+    Press(KeyCode),
+    Release(KeyCode),
+    /// Press and release a key
+    Tap(KeyCode),
+    /// Wait for some milliseconds
+    Delay(u32),
+}
+
+impl MacroStep {
+    fn to_word(self) -> u32 {
+        match self {
+            MacroStep::Press(code) => 0x0100_0000 | code as u32,
+            MacroStep::Release(code) => 0x0200_0000 | code as u32,
+            MacroStep::Tap(code) => 0x0300_0000 | code as u32,
+            MacroStep::Delay(delay) => 0x0400_0000 | (delay & 0x00ff_ffff),
+        }
+    }
+
+    fn from_word(word: u32) -> Option<MacroStep> {
+        let code = KeyCode::from_u8(word as u8);
+        match word >> 24 {
+            0x01 => code.map(MacroStep::Press),
+            0x02 => code.map(MacroStep::Release),
+            0x03 => code.map(MacroStep::Tap),
+            0x04 => Some(MacroStep::Delay(word & 0x00ff_ffff)),
+            _ => None,
+        }
+    }
+}
+
+pub type Macro = &'static [MacroStep];
+
+/// Where the steps played by `MacroPlayer` come from
+#[derive(Copy, Clone)]
+enum Source {
+    Table(Macro),
+    Recorded,
+}
+
+/// Plays back `Action::Macro`, one step per call to `tick`.
+///
+/// Keys held by the macro are added on top of the keys held on the
+/// keyboard, see `add_to`.
+pub struct MacroPlayer {
+    source: Source,
+    len: usize,
+    /// Index of the next step in `steps`
+    next: usize,
+    /// The key of a `MacroStep::Tap` is down
+    tapping: bool,
+    /// Keys and modifiers held down by the macro
+    report: HidReport,
+    /// `report` changed, but has not been sent yet
+    unsent: bool,
+    /// `report` was sent over Bluetooth, but USB has yet to deliver
+    /// it to the host
+    usb_pending: bool,
+    /// Start and length of the current `MacroStep::Delay`
+    since: u32,
+    delay: u32,
+}
+
+impl MacroPlayer {
+    pub const fn new() -> MacroPlayer {
+        MacroPlayer {
+            source: Source::Table(&[]),
+            len: 0,
+            next: 0,
+            tapping: false,
+            report: HidReport::new(),
+            unsent: false,
+            usb_pending: false,
+            since: 0,
+            delay: 0,
+        }
+    }
+
+    /// Start playing `steps`, unless a macro is already playing.
+    pub fn play(&mut self, steps: Macro) {
+        self.start(Source::Table(steps), steps.len());
+    }
+
+    /// Start playing the first `len` steps of the macro recorded by
+    /// `MacroRecorder`, which must be passed to `tick`.
+    pub fn play_recorded(&mut self, len: usize) {
+        self.start(Source::Recorded, len);
+    }
+
+    fn start(&mut self, source: Source, len: usize) {
+        if !self.is_playing() {
+            self.source = source;
+            self.len = len;
+            self.next = 0;
+            self.delay = 0;
+        }
+    }
+
+    pub fn is_playing(&self) -> bool {
+        self.next < self.len || self.unsent || self.usb_pending || !self.report.is_empty()
+    }
+
+    /// Play the next step. Returns whether the report must be sent.
+    ///
+    /// A report that could not be sent is retried before moving on,
+    /// see `sent`, and a report not yet delivered over USB is waited
+    /// for, see `usb_sent`.
+    pub fn tick(&mut self, now: u32, recorded: &[MacroStep]) -> bool {
+        if self.unsent {
+            return true;
+        }
+        if self.usb_pending {
+            return false;
+        }
+        if elapsed(now, self.since) < self.delay {
+            return false;
+        }
+        self.delay = 0;
+
+        let steps = match self.source {
+            Source::Table(steps) => steps,
+            Source::Recorded => recorded,
+        };
+        let step = match steps.get(self.next).filter(|_| self.next < self.len) {
+            Some(&step) => step,
+            None if !self.report.is_empty() => {
+                // don't leave keys stuck if the macro did not release them
+                self.report = HidReport::new();
+                self.unsent = true;
+                return true;
+            }
+            None => return false,
+        };
+        match step {
+            MacroStep::Press(code) => self.report.press(code),
+            MacroStep::Release(code) => self.report.release(code),
+            MacroStep::Tap(code) if !self.tapping => {
+                self.report.press(code);
+                self.tapping = true;
+                self.unsent = true;
+                return true;
+            }
+            MacroStep::Tap(code) => {
+                self.report.release(code);
+                self.tapping = false;
+            }
+            MacroStep::Delay(delay) => {
+                self.since = now;
+                self.delay = delay;
+                self.next += 1;
+                return false;
+            }
+        }
+        self.next += 1;
+        self.unsent = true;
+        true
+    }
+
+    /// The report for the current step was sent over Bluetooth, and
+    /// handed over to USB if `over_usb`.
+    pub fn sent(&mut self, over_usb: bool) {
+        self.unsent = false;
+        self.usb_pending = over_usb;
+    }
+
+    /// USB delivered the last report handed over to it.
+    pub fn usb_sent(&mut self) {
+        self.usb_pending = false;
+    }
+
+    /// Add the keys held by the macro to `report`.
+    pub fn add_to(&self, report: &mut HidReport) {
+        report.modifiers |= self.report.modifiers;
+        for &key in self.report.keys.iter().filter(|&&key| key != 0) {
+            if let Some(slot) = report.keys.iter_mut().find(|k| **k == 0) {
+                *slot = key;
+            }
+        }
+    }
+}
+
+/// Longest macro `MacroRecorder` can hold
+pub const RECORDED_STEPS: usize = 64;
+
+/// Marks a valid recorded macro in the EEPROM, along with its length
+/// in the low half-word
+const RECORDED_MAGIC: u32 = 0x4d52_0000;
+
+/// Records the keys typed on the keyboard into RAM, for playback with
+/// `MacroPlayer::play_recorded`.
+///
+/// The recording can be saved to the data EEPROM. Saving writes one
+/// word per call to `save`, so it does not hold up the key scan.
+pub struct MacroRecorder {
+    steps: [MacroStep; RECORDED_STEPS],
+    len: usize,
+    recording: bool,
+    /// Position of the next word to save, if saving. The header is
+    /// cleared first and written last, so that an interrupted save
+    /// leaves no macro rather than a broken one.
+    saving: Option<usize>,
+}
+
+impl MacroRecorder {
+    pub const fn new() -> MacroRecorder {
+        MacroRecorder {
+            steps: [MacroStep::Delay(0); RECORDED_STEPS],
+            len: 0,
+            recording: false,
+            saving: None,
+        }
+    }
+
+    pub fn is_recording(&self) -> bool {
+        self.recording
+    }
+
+    /// The steps recorded so far
+    pub fn steps(&self) -> &[MacroStep] {
+        &self.steps[..self.len]
+    }
+
+    pub fn start(&mut self) {
+        self.recording = true;
+        self.len = 0;
+        self.saving = None;
+    }
+
+    /// Stop recording, and save the macro if `persist` is set.
+    pub fn stop(&mut self, persist: bool) {
+        if self.recording {
+            self.recording = false;
+            if persist {
+                self.saving = Some(0);
+            }
+        }
+    }
+
+    /// Append `step`, stopping once the buffer is full.
+    pub fn record(&mut self, step: MacroStep, persist: bool) {
+        if self.recording {
+            self.steps[self.len] = step;
+            self.len += 1;
+            if self.len == RECORDED_STEPS {
+                self.stop(persist);
+            }
+        }
+    }
+
+    /// Restore the macro saved in `eeprom`, if there is one.
+    pub fn load(&mut self, eeprom: &Eeprom) {
+        let header = eeprom.read_word(RECORDED_MACRO);
+        let len = (header & 0xffff) as usize;
+        if header & 0xffff_0000 != RECORDED_MAGIC || len > RECORDED_STEPS {
+            return;
+        }
+        for i in 0..len {
+            match MacroStep::from_word(eeprom.read_word(RECORDED_MACRO + 4 * (i + 1))) {
+                Some(step) => self.steps[i] = step,
+                None => return,
+            }
+        }
+        self.len = len;
+    }
+
+    /// Write the next word of a pending save to `eeprom`.
+    pub fn save(&mut self, eeprom: &mut Eeprom) -> nb::Result<(), EepromError> {
+        let position = match self.saving {
+            Some(position) => position,
+            None => return Ok(()),
+        };
+        let (word, value) = match position {
+            0 => (0, 0),
+            position if position <= self.len => (position, self.steps[position - 1].to_word()),
+            _ => (0, RECORDED_MAGIC | self.len as u32),
+        };
+
+        let result = eeprom.write_word(RECORDED_MACRO + 4 * word, value);
+        match result {
+            Err(nb::Error::WouldBlock) => {}
+            // give up rather than retrying forever
+            Err(_) => self.saving = None,
+            Ok(()) if position > self.len => self.saving = None,
+            Ok(()) => self.saving = Some(position + 1),
+        }
+        result
+    }
+}
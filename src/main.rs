@@ -1,12 +1,14 @@
 #![feature(const_fn)]
 #![feature(never_type)]
 #![feature(unsize)]
-#![no_main]
-#![no_std]
+// The tests run on the host, with std, see `make test`
+#![cfg_attr(not(test), no_main)]
+#![cfg_attr(not(test), no_std)]
+#![cfg_attr(test, allow(dead_code, unused_imports))]
 
-#[cfg(not(feature = "use_semihosting"))]
+#[cfg(all(not(test), not(feature = "use_semihosting")))]
 extern crate panic_abort;
-#[cfg(feature = "use_semihosting")]
+#[cfg(all(not(test), feature = "use_semihosting"))]
 extern crate panic_semihosting;
 #[cfg(feature = "use_semihosting")]
 use cortex_m_semihosting::heprintln;
@@ -17,22 +19,40 @@ mod debug;
 mod action;
 mod bluetooth;
 mod clock;
+mod codec;
+mod combo;
+mod crc;
+mod debounce;
+mod eeprom;
+mod event;
+mod ghosting;
 mod hidreport;
 mod keyboard;
 mod keycodes;
+mod keymap;
 mod keymatrix;
 mod layout;
+mod leader;
 mod led;
+mod macros;
+mod mouse;
+mod overrides;
 mod protocol;
+mod record_log;
 mod serial;
+mod settings;
+mod tapping;
 mod theme;
 mod usb;
 
 use hal::dma::DmaExt;
 use hal::gpio::GpioExt;
 use rtfm::app;
+use rtfm::Mutex;
 
 use crate::bluetooth::Bluetooth;
+use crate::debug::UnwrapLog;
+use crate::eeprom::Eeprom;
 use crate::keyboard::Keyboard;
 use crate::keymatrix::KeyMatrix;
 use crate::led::Led;
@@ -41,6 +61,7 @@ use crate::serial::led_usart::LedUsart;
 use crate::serial::Serial;
 use crate::usb::Usb;
 
+#[cfg(not(test))]
 #[app(device = stm32l1::stm32l151)]
 const APP: () = {
     static mut KEYBOARD: Keyboard = Keyboard::new();
@@ -54,14 +75,15 @@ const APP: () = {
     static mut SYST: stm32l1::stm32l151::SYST = ();
     static mut EXTI: stm32l1::stm32l151::EXTI = ();
     static mut USB: Usb = ();
+    static mut EEPROM: Eeprom = ();
 
-    #[init(resources = [BLUETOOTH_BUFFERS, LED_BUFFERS])]
+    #[init(resources = [BLUETOOTH_BUFFERS, LED_BUFFERS, KEYBOARD])]
     fn init() -> init::LateResources {
         // re-locate vector table to 0x80004000 because bootloader uses 0x80000000
         unsafe { core.SCB.vtor.write(0x4000) };
 
         clock::init_clock(&device);
-        clock::enable_tick(&mut core.SYST, 100_000);
+        clock::enable_tick(&mut core.SYST, clock::SYSCLK_HZ / 1000 * clock::TICK_MS);
 
         let dma = device.DMA1.split();
         let gpioa = device.GPIOA.split();
@@ -96,6 +118,10 @@ const APP: () = {
 
         let key_matrix = KeyMatrix::new(row_pins, column_pins);
 
+        let eeprom = Eeprom::new(device.FLASH);
+        resources.KEYBOARD.load(&eeprom);
+        let settings = resources.KEYBOARD.settings.get().copied();
+
         let led_usart = LedUsart::new(
             device.USART3,
             gpiob.pb10,
@@ -108,7 +134,10 @@ const APP: () = {
         let led_serial = Serial::new(led_usart, &mut led_send_buffer[0]);
         let mut led = Led::new(led_serial, &mut led_receive_buffer[0], gpioc.pc15);
         led.poke(&core.SYST).unwrap();
-        led.theme_mode().unwrap();
+        match settings {
+            Some(settings) => led.restore(settings.led_on, settings.led).unwrap(),
+            None => led.theme_mode().unwrap(),
+        }
 
         let bluetooth_usart = BluetoothUsart::new(
             device.USART2,
@@ -121,7 +150,13 @@ const APP: () = {
         );
         let (bt_send_buffer, bt_receive_buffer) = resources.BLUETOOTH_BUFFERS.split_at_mut(1);
         let bluetooth_serial = Serial::new(bluetooth_usart, &mut bt_send_buffer[0]);
-        let bluetooth = Bluetooth::new(bluetooth_serial, &mut bt_receive_buffer[0]);
+        let mut bluetooth = Bluetooth::new(bluetooth_serial, &mut bt_receive_buffer[0]);
+        if let Some(settings) = settings {
+            resources.KEYBOARD.send_usb_report = settings.send_usb_report;
+            if settings.bluetooth_host != 0 {
+                bluetooth.connect_host(settings.bluetooth_host).log_error();
+            }
+        }
 
         let usb = Usb::new(device.USB, &mut device.RCC, &mut device.SYSCFG);
 
@@ -132,10 +167,11 @@ const APP: () = {
             SYST: core.SYST,
             EXTI: device.EXTI,
             USB: usb,
+            EEPROM: eeprom,
         }
     }
 
-    #[exception(resources = [BLUETOOTH, LED, KEY_MATRIX, SYST, KEYBOARD, USB])]
+    #[exception(resources = [BLUETOOTH, LED, KEY_MATRIX, SYST, KEYBOARD, USB, EEPROM])]
     fn SysTick() {
         resources.KEY_MATRIX.sample(&resources.SYST);
         resources.KEYBOARD.process(
@@ -144,11 +180,22 @@ const APP: () = {
             &mut resources.LED,
             &mut resources.USB,
         );
+        resources.KEYBOARD.save(&mut resources.EEPROM);
+        resources.USB.tick();
     }
 
-    #[idle]
+    #[idle(resources = [KEY_MATRIX])]
     fn idle() -> ! {
+        let mut ghosts_reported = 0;
         loop {
+            // report held back ghost keys here rather than in the scan
+            let ghosts = resources
+                .KEY_MATRIX
+                .lock(|key_matrix| key_matrix.ghost_filter.blocked());
+            if ghosts != ghosts_reported {
+                crate::heprintln!("blocked ghost keys: {}", ghosts).ok();
+                ghosts_reported = ghosts;
+            }
             cortex_m::asm::wfi();
         }
     }
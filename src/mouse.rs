@@ -0,0 +1,240 @@
+use crate::action::Action;
+use crate::hidreport::MouseReport;
+
+#[allow(dead_code)]
+#[derive(Copy, Clone, PartialEq)]
+pub enum AccelerationCurve {
+    Linear,
+    /// Speeds up slowly at first, for finer control
+    Quadratic,
+}
+
+pub struct MouseConfig {
+    /// Milliseconds between reports while the cursor moves
+    pub interval: u32,
+    /// Pixels per report right after pressing a key
+    pub speed: u8,
+    /// Pixels per report at full speed
+    pub max_speed: u8,
+    /// Milliseconds held before the cursor speeds up
+    pub delay: u32,
+    /// Milliseconds it takes after `delay` to reach `max_speed`
+    pub time_to_max: u32,
+    pub curve: AccelerationCurve,
+    /// Milliseconds between wheel steps
+    pub wheel_interval: u32,
+}
+
+const UP: u8 = 1 << 0;
+const DOWN: u8 = 1 << 1;
+const LEFT: u8 = 1 << 2;
+const RIGHT: u8 = 1 << 3;
+const WHEEL_UP: u8 = 1 << 4;
+const WHEEL_DOWN: u8 = 1 << 5;
+const MOVE: u8 = UP | DOWN | LEFT | RIGHT;
+const WHEEL: u8 = WHEEL_UP | WHEEL_DOWN;
+
+/// Moves the cursor while mouse keys are held, see `Action::MouseUp`
+/// etc.
+pub struct MouseKeys {
+    /// Buttons and directions of the keys seen in the current scan
+    next_buttons: u8,
+    next_directions: u8,
+    buttons: u8,
+    directions: u8,
+    /// When the cursor started moving
+    since: u32,
+    /// When the cursor or wheel last moved, `None` to move right away
+    moved: Option<u32>,
+    wheeled: Option<u32>,
+    /// Whether the buttons changed since the last report
+    changed: bool,
+}
+
+impl MouseKeys {
+    pub const fn new() -> MouseKeys {
+        MouseKeys {
+            next_buttons: 0,
+            next_directions: 0,
+            buttons: 0,
+            directions: 0,
+            since: 0,
+            moved: None,
+            wheeled: None,
+            changed: false,
+        }
+    }
+
+    /// Note the pressed mouse key `action` for the current scan.
+    pub fn press(&mut self, action: Action) {
+        match action {
+            Action::MouseUp => self.next_directions |= UP,
+            Action::MouseDown => self.next_directions |= DOWN,
+            Action::MouseLeft => self.next_directions |= LEFT,
+            Action::MouseRight => self.next_directions |= RIGHT,
+            Action::MouseWheelUp => self.next_directions |= WHEEL_UP,
+            Action::MouseWheelDown => self.next_directions |= WHEEL_DOWN,
+            Action::MouseButton(mask) => self.next_buttons |= mask,
+            _ => {}
+        }
+    }
+
+    /// Take over the keys seen in the scan that just finished.
+    pub fn finish(&mut self, now: u32) {
+        if self.directions & MOVE == 0 && self.next_directions & MOVE != 0 {
+            self.since = now;
+            self.moved = None;
+        }
+        if self.directions & WHEEL == 0 && self.next_directions & WHEEL != 0 {
+            self.wheeled = None;
+        }
+        if self.next_buttons != self.buttons {
+            self.changed = true;
+        }
+        self.buttons = self.next_buttons;
+        self.directions = self.next_directions;
+        self.next_buttons = 0;
+        self.next_directions = 0;
+    }
+
+    /// The report to send at `now`, if any. Called on every tick, as
+    /// the cursor keeps moving while the keys are held.
+    pub fn tick(&mut self, config: &MouseConfig, now: u32) -> Option<MouseReport> {
+        let mut report = MouseReport {
+            buttons: self.buttons,
+            ..MouseReport::default()
+        };
+        let mut send = self.changed;
+        self.changed = false;
+
+        let due =
+            |last: Option<u32>, interval| last.map_or(true, |t| now.wrapping_sub(t) >= interval);
+        if self.directions & MOVE != 0 && due(self.moved, config.interval) {
+            self.moved = Some(now);
+            let speed = self.speed(config, now);
+            if self.directions & UP != 0 {
+                report.y -= speed;
+            }
+            if self.directions & DOWN != 0 {
+                report.y += speed;
+            }
+            if self.directions & LEFT != 0 {
+                report.x -= speed;
+            }
+            if self.directions & RIGHT != 0 {
+                report.x += speed;
+            }
+            send = true;
+        }
+        if self.directions & WHEEL != 0 && due(self.wheeled, config.wheel_interval) {
+            self.wheeled = Some(now);
+            if self.directions & WHEEL_UP != 0 {
+                report.wheel += 1;
+            }
+            if self.directions & WHEEL_DOWN != 0 {
+                report.wheel -= 1;
+            }
+            send = true;
+        }
+
+        if send {
+            Some(report)
+        } else {
+            None
+        }
+    }
+
+    /// Pixels to move per report, after the cursor moved since
+    /// `self.since`
+    fn speed(&self, config: &MouseConfig, now: u32) -> i8 {
+        let held = now.wrapping_sub(self.since);
+        let (speed, max_speed) = (u64::from(config.speed), u64::from(config.max_speed));
+        if held <= config.delay || max_speed <= speed {
+            return speed.min(127) as i8;
+        }
+        if config.time_to_max == 0 {
+            return max_speed.min(127) as i8;
+        }
+        let ramp = u64::from((held - config.delay).min(config.time_to_max));
+        let time_to_max = u64::from(config.time_to_max);
+        let gain = match config.curve {
+            AccelerationCurve::Linear => (max_speed - speed) * ramp / time_to_max,
+            AccelerationCurve::Quadratic => {
+                (max_speed - speed) * ramp * ramp / (time_to_max * time_to_max)
+            }
+        };
+        (speed + gain).min(127) as i8
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: MouseConfig = MouseConfig {
+        interval: 15,
+        speed: 2,
+        max_speed: 22,
+        delay: 100,
+        time_to_max: 1000,
+        curve: AccelerationCurve::Linear,
+        wheel_interval: 90,
+    };
+
+    /// Hold `actions` through the scan at `now`, and tick.
+    fn scan(mouse: &mut MouseKeys, actions: &[Action], now: u32) -> Option<MouseReport> {
+        for &action in actions {
+            mouse.press(action);
+        }
+        mouse.finish(now);
+        mouse.tick(&CONFIG, now)
+    }
+
+    #[test]
+    fn speeds_up_along_the_curve() {
+        let mut mouse = MouseKeys::new();
+        mouse.since = u32::max_value() - 50;
+        let at = |ms: u32| mouse.since.wrapping_add(ms);
+        let quadratic = MouseConfig {
+            curve: AccelerationCurve::Quadratic,
+            ..CONFIG
+        };
+        for &(held, linear, squared) in [
+            (0, 2, 2),
+            (100, 2, 2),
+            (600, 12, 7),
+            (1100, 22, 22),
+            (5000, 22, 22),
+        ]
+        .iter()
+        {
+            assert_eq!(mouse.speed(&CONFIG, at(held)), linear, "{} ms", held);
+            assert_eq!(mouse.speed(&quadratic, at(held)), squared, "{} ms", held);
+        }
+    }
+
+    #[test]
+    fn moves_while_held() {
+        let mut mouse = MouseKeys::new();
+        let keys = [Action::MouseRight, Action::MouseUp];
+        let report = scan(&mut mouse, &keys, 1000).unwrap();
+        assert_eq!((report.x, report.y), (2, -2));
+        assert!(scan(&mut mouse, &keys, 1010).is_none());
+        assert!(scan(&mut mouse, &keys, 1015).is_some());
+
+        let report = scan(&mut mouse, &[Action::MouseWheelDown], 1020).unwrap();
+        assert_eq!((report.x, report.y, report.wheel), (0, 0, -1));
+        assert!(scan(&mut mouse, &[Action::MouseWheelDown], 1050).is_none());
+        assert!(scan(&mut mouse, &[], 1200).is_none());
+    }
+
+    #[test]
+    fn reports_button_changes() {
+        let mut mouse = MouseKeys::new();
+        let left = [Action::MouseButton(1)];
+        assert_eq!(scan(&mut mouse, &left, 0).unwrap().buttons, 1);
+        assert!(scan(&mut mouse, &left, 3).is_none());
+        assert_eq!(scan(&mut mouse, &[], 6).unwrap().buttons, 0);
+        assert!(scan(&mut mouse, &[], 9).is_none());
+    }
+}
@@ -0,0 +1,95 @@
+use crate::hidreport::{HidReport, NkroReport};
+use crate::keycodes::KeyCode;
+use bit_field::BitArray;
+
+/// Sends `replacement` instead of `key` while one of `mods` is held,
+/// for example Delete for Shift+Backspace
+pub struct KeyOverride {
+    /// Modifier bits, see `keycodes::MOD_LCTRL` etc.
+    pub mods: u8,
+    pub key: KeyCode,
+    pub replacement: KeyCode,
+}
+
+impl KeyOverride {
+    /// Replace the key in `report`, or in `rollover` holding the keys
+    /// that did not fit into it, and mask out the modifiers that
+    /// triggered the override. Both the 6KRO and the NKRO report are
+    /// built from these two.
+    pub fn apply(&self, report: &mut HidReport, rollover: &mut NkroReport) {
+        if (report.modifiers | rollover.modifiers) & self.mods == 0 {
+            return;
+        }
+        if let Some(key) = report.keys.iter_mut().find(|k| **k == self.key as u8) {
+            *key = self.replacement as u8;
+        } else if rollover.keys.get_bit(self.key as usize) {
+            rollover.keys.set_bit(self.key as usize, false);
+            rollover.press(self.replacement);
+        } else {
+            return;
+        }
+        report.modifiers &= !self.mods;
+        rollover.modifiers &= !self.mods;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keycodes::{MOD_LSHIFT, MOD_SHIFT};
+
+    const SHIFT_BACKSPACE: KeyOverride = KeyOverride {
+        mods: MOD_SHIFT,
+        key: KeyCode::BSpace,
+        replacement: KeyCode::Delete,
+    };
+
+    #[test]
+    fn replaces_in_the_report() {
+        let mut report = HidReport::new();
+        let mut rollover = NkroReport::new();
+        report.modifiers = MOD_LSHIFT;
+        report.press(KeyCode::BSpace);
+        SHIFT_BACKSPACE.apply(&mut report, &mut rollover);
+        assert_eq!(report.keys[0], KeyCode::Delete as u8);
+        assert_eq!(report.modifiers, 0);
+    }
+
+    #[test]
+    fn replaces_in_the_rollover() {
+        let mut report = HidReport::new();
+        let mut rollover = NkroReport::new();
+        report.modifiers = MOD_LSHIFT;
+        for &code in [
+            KeyCode::A,
+            KeyCode::B,
+            KeyCode::C,
+            KeyCode::D,
+            KeyCode::E,
+            KeyCode::F,
+        ]
+        .iter()
+        {
+            report.press(code);
+        }
+        rollover.press(KeyCode::BSpace);
+        SHIFT_BACKSPACE.apply(&mut report, &mut rollover);
+        assert!(!rollover.keys.get_bit(KeyCode::BSpace as usize));
+        assert!(rollover.keys.get_bit(KeyCode::Delete as usize));
+        assert_eq!(report.modifiers, 0);
+
+        let mut nkro = rollover;
+        nkro.add(&report);
+        assert_eq!(nkro.modifiers, 0);
+        assert!(nkro.keys.get_bit(KeyCode::Delete as usize));
+    }
+
+    #[test]
+    fn needs_the_modifier() {
+        let mut report = HidReport::new();
+        let mut rollover = NkroReport::new();
+        report.press(KeyCode::BSpace);
+        SHIFT_BACKSPACE.apply(&mut report, &mut rollover);
+        assert_eq!(report.keys[0], KeyCode::BSpace as u8);
+    }
+}
@@ -80,6 +80,9 @@ impl From<u8> for BleOp {
     }
 }
 
+/// Operations of `MsgType::Keyboard`. Only 8-byte keyboard reports
+/// are known, there is no operation for consumer, system or mouse
+/// reports.
 #[repr(u8)]
 #[non_exhaustive]
 #[derive(Debug, Copy, Clone)]
@@ -0,0 +1,574 @@
+use crate::crc::Crc32;
+
+/// Word-addressed non-volatile memory, such as [`eeprom::Eeprom`]
+pub trait Storage {
+    type Error;
+
+    fn read_word(&self, offset: usize) -> u32;
+
+    /// Start writing `word` at `offset`, `WouldBlock` while an
+    /// earlier write is still under way
+    fn write_word(&mut self, offset: usize, word: u32) -> nb::Result<(), Self::Error>;
+}
+
+/// Records a log can tell apart
+pub const RECORD_KEYS: usize = 4;
+/// Most words of data a record can hold
+pub const RECORD_WORDS: usize = 8;
+
+/// Marks a sector in use, in its first word
+const SECTOR_MAGIC: u32 = 0x4c4f_4753;
+/// Words at the start of a sector: the magic and the generation
+const SECTOR_HEADER: usize = 2;
+/// Marks a record, along with its key in the second byte and its
+/// number of data words in the low byte
+const RECORD_MAGIC: u32 = 0x5200_0000;
+
+#[derive(Debug)]
+pub enum LogError<E> {
+    /// The key or the length of the record is out of bounds
+    OutOfRange,
+    Storage(E),
+}
+
+/// Steps of moving to the other sector
+#[derive(Copy, Clone)]
+enum Stage {
+    /// Clear a word of the other sector, starting with its magic. All
+    /// of it is cleared, as records left over from an earlier move
+    /// that was cut short have the same generation and would pass
+    /// their CRC.
+    Clear(usize),
+    /// Copy the latest record of the key
+    Copy(usize),
+    /// Append the record being written
+    Append,
+    Generation,
+    Magic,
+}
+
+/// Copy of the latest records into the other sector, once the active
+/// one is full
+#[derive(Copy, Clone)]
+struct Compaction {
+    stage: Stage,
+    /// Where the next record goes
+    head: usize,
+    /// Position of the latest record of each key in the other sector
+    latest: [Option<usize>; RECORD_KEYS],
+}
+
+/// Log of keyed records, such as the settings, spread over two
+/// sectors of storage to spread out the wear.
+///
+/// Writing a record appends it to the active sector, rather than
+/// overwriting the previous one. Once that sector is full, the latest
+/// record of every key is copied to the other sector, which then
+/// becomes the active one with the next generation.
+///
+/// Each record is a header word, the data and a CRC-32 over the
+/// generation, header and data. The header is written last, and the
+/// CRC makes records left over from older generations or cut short by
+/// a power loss invalid, so reading stops right before them. A sector
+/// becomes active only once its magic is written, after all the
+/// records it takes over.
+pub struct RecordLog {
+    /// Byte offset of the log in the storage
+    base: usize,
+    /// Words per sector
+    sector_words: usize,
+    active: usize,
+    generation: u32,
+    /// Position of the next record in the active sector, in words
+    head: usize,
+    /// Position of the latest record of each key in the active sector
+    latest: [Option<usize>; RECORD_KEYS],
+    compaction: Option<Compaction>,
+    /// Words of the record being appended written so far
+    written: usize,
+}
+
+impl RecordLog {
+    /// A log over the `size` bytes at `base`, which must fit the
+    /// latest record of every key in half of them
+    pub const fn new(base: usize, size: usize) -> RecordLog {
+        RecordLog {
+            base,
+            sector_words: size / 8,
+            // no sector is in use until `mount` finds one, so the
+            // first append starts the log in sector 0
+            active: 1,
+            generation: 0,
+            head: usize::max_value(),
+            latest: [None; RECORD_KEYS],
+            compaction: None,
+            written: 0,
+        }
+    }
+
+    /// Find the active sector and its records in `storage`.
+    pub fn mount<S: Storage>(&mut self, storage: &S) {
+        let generations = [
+            self.generation_of(storage, 0),
+            self.generation_of(storage, 1),
+        ];
+        let active = match generations {
+            [Some(first), Some(second)] if (second.wrapping_sub(first) as i32) > 0 => 1,
+            [Some(_), _] => 0,
+            [None, Some(_)] => 1,
+            [None, None] => return,
+        };
+        self.active = active;
+        self.generation = generations[active].unwrap_or(0);
+        self.latest = [None; RECORD_KEYS];
+        self.compaction = None;
+        self.written = 0;
+
+        let mut position = SECTOR_HEADER;
+        while let Some((key, len)) = self.record_at(storage, active, self.generation, position) {
+            self.latest[key] = Some(position);
+            position += len + 2;
+        }
+        self.head = position;
+    }
+
+    /// Copy the latest record of `key` into `buffer`, returning its
+    /// length.
+    pub fn read<S: Storage>(&self, storage: &S, key: usize, buffer: &mut [u32]) -> Option<usize> {
+        let position = (*self.latest.get(key)?)?;
+        let len = self.header(storage, self.active, position) as u8 as usize;
+        if len > buffer.len() {
+            return None;
+        }
+        for (i, word) in buffer[..len].iter_mut().enumerate() {
+            *word = storage.read_word(self.offset(self.active, position + 1 + i));
+        }
+        Some(len)
+    }
+
+    /// Write the next word of the record `data` for `key`, returning
+    /// `WouldBlock` until all of it is written. The same record must
+    /// be passed until then.
+    pub fn append<S: Storage>(
+        &mut self,
+        storage: &mut S,
+        key: usize,
+        data: &[u32],
+    ) -> nb::Result<(), LogError<S::Error>> {
+        if key >= RECORD_KEYS || data.len() > RECORD_WORDS {
+            return Err(nb::Error::Other(LogError::OutOfRange));
+        }
+        let result = if self.compaction.is_none()
+            && self.head.saturating_add(data.len() + 2) <= self.sector_words
+        {
+            let (sector, generation, position) = (self.active, self.generation, self.head);
+            self.write_record(storage, sector, generation, position, key, data)
+                .map(|()| {
+                    self.latest[key] = Some(position);
+                    self.head += data.len() + 2;
+                })
+        } else {
+            self.compact(storage, key, data)
+        };
+        if let Err(nb::Error::Other(_)) = result {
+            // start over with the next record
+            self.compaction = None;
+            self.written = 0;
+        }
+        result
+    }
+
+    /// Take the next step of moving to the other sector, appending
+    /// `data` there along the way.
+    fn compact<S: Storage>(
+        &mut self,
+        storage: &mut S,
+        key: usize,
+        data: &[u32],
+    ) -> nb::Result<(), LogError<S::Error>> {
+        let target = 1 - self.active;
+        let generation = self.generation.wrapping_add(1);
+        let mut compaction = self.compaction.unwrap_or(Compaction {
+            stage: Stage::Clear(0),
+            head: SECTOR_HEADER,
+            latest: [None; RECORD_KEYS],
+        });
+
+        match compaction.stage {
+            Stage::Clear(index) => {
+                self.write(storage, target, index, 0)?;
+                // skip the words that are clear already
+                compaction.stage = (index + 1..self.sector_words)
+                    .find(|&index| storage.read_word(self.offset(target, index)) != 0)
+                    .map_or_else(|| self.next_copy(0, key), Stage::Clear);
+            }
+            Stage::Copy(copied) => {
+                let mut buffer = [0; RECORD_WORDS];
+                let len = self.read(storage, copied, &mut buffer).unwrap_or(0);
+                let head = compaction.head;
+                self.write_record(storage, target, generation, head, copied, &buffer[..len])?;
+                compaction.latest[copied] = Some(head);
+                compaction.head += len + 2;
+                compaction.stage = self.next_copy(copied + 1, key);
+            }
+            Stage::Append => {
+                let head = compaction.head;
+                self.write_record(storage, target, generation, head, key, data)?;
+                compaction.latest[key] = Some(head);
+                compaction.head += data.len() + 2;
+                compaction.stage = Stage::Generation;
+            }
+            Stage::Generation => {
+                self.write(storage, target, 1, generation)?;
+                compaction.stage = Stage::Magic;
+            }
+            Stage::Magic => {
+                self.write(storage, target, 0, SECTOR_MAGIC)?;
+                self.active = target;
+                self.generation = generation;
+                self.head = compaction.head;
+                self.latest = compaction.latest;
+                self.compaction = None;
+                return Ok(());
+            }
+        }
+        self.compaction = Some(compaction);
+        Err(nb::Error::WouldBlock)
+    }
+
+    /// The stage copying the first key from `first` on with a record
+    /// to take over, other than the `appended` one
+    fn next_copy(&self, first: usize, appended: usize) -> Stage {
+        (first..RECORD_KEYS)
+            .find(|&key| key != appended && self.latest[key].is_some())
+            .map_or(Stage::Append, Stage::Copy)
+    }
+
+    /// Write the next word of a record at `position`: the data, the
+    /// CRC and then the header.
+    fn write_record<S: Storage>(
+        &mut self,
+        storage: &mut S,
+        sector: usize,
+        generation: u32,
+        position: usize,
+        key: usize,
+        data: &[u32],
+    ) -> nb::Result<(), LogError<S::Error>> {
+        if position + data.len() + 2 > self.sector_words {
+            return Err(nb::Error::Other(LogError::OutOfRange));
+        }
+        let header = RECORD_MAGIC | (key as u32) << 8 | data.len() as u32;
+        let (index, word) = match self.written {
+            written if written < data.len() => (position + 1 + written, data[written]),
+            written if written == data.len() => {
+                (position + 1 + written, record_crc(generation, header, data))
+            }
+            _ => (position, header),
+        };
+        self.write(storage, sector, index, word)?;
+        self.written += 1;
+        if self.written < data.len() + 2 {
+            return Err(nb::Error::WouldBlock);
+        }
+        self.written = 0;
+        Ok(())
+    }
+
+    fn write<S: Storage>(
+        &self,
+        storage: &mut S,
+        sector: usize,
+        index: usize,
+        word: u32,
+    ) -> nb::Result<(), LogError<S::Error>> {
+        storage
+            .write_word(self.offset(sector, index), word)
+            .map_err(|e| match e {
+                nb::Error::WouldBlock => nb::Error::WouldBlock,
+                nb::Error::Other(e) => nb::Error::Other(LogError::Storage(e)),
+            })
+    }
+
+    /// The generation of `sector`, if it is in use
+    fn generation_of<S: Storage>(&self, storage: &S, sector: usize) -> Option<u32> {
+        if storage.read_word(self.offset(sector, 0)) == SECTOR_MAGIC {
+            Some(storage.read_word(self.offset(sector, 1)))
+        } else {
+            None
+        }
+    }
+
+    fn header<S: Storage>(&self, storage: &S, sector: usize, position: usize) -> u32 {
+        storage.read_word(self.offset(sector, position))
+    }
+
+    /// The key and length of a valid record at `position`, if there
+    /// is one
+    fn record_at<S: Storage>(
+        &self,
+        storage: &S,
+        sector: usize,
+        generation: u32,
+        position: usize,
+    ) -> Option<(usize, usize)> {
+        if position + 2 > self.sector_words {
+            return None;
+        }
+        let header = self.header(storage, sector, position);
+        let (key, len) = ((header >> 8) as u8 as usize, header as u8 as usize);
+        if header & 0xffff_0000 != RECORD_MAGIC
+            || key >= RECORD_KEYS
+            || len > RECORD_WORDS
+            || position + len + 2 > self.sector_words
+        {
+            return None;
+        }
+        let mut data = [0; RECORD_WORDS];
+        for (i, word) in data[..len].iter_mut().enumerate() {
+            *word = storage.read_word(self.offset(sector, position + 1 + i));
+        }
+        let crc = storage.read_word(self.offset(sector, position + 1 + len));
+        if crc != record_crc(generation, header, &data[..len]) {
+            return None;
+        }
+        Some((key, len))
+    }
+
+    /// Byte offset of word `index` of `sector` in the storage
+    fn offset(&self, sector: usize, index: usize) -> usize {
+        self.base + 4 * (sector * self.sector_words + index)
+    }
+}
+
+fn record_crc(generation: u32, header: u32, data: &[u32]) -> u32 {
+    let mut crc = Crc32::new();
+    crc.update_word(generation);
+    crc.update_word(header);
+    for &word in data {
+        crc.update_word(word);
+    }
+    crc.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Two sectors of 32 words
+    const SIZE: usize = 256;
+
+    /// Storage in RAM, which is busy for every other write like the
+    /// EEPROM, and which loses power after a number of writes
+    struct Ram {
+        words: [u32; SIZE / 4],
+        busy: bool,
+        /// Writes that still make it into `words`
+        writes_left: usize,
+        /// A write was lost
+        lost: bool,
+        /// Writes that made it into `words`
+        written: usize,
+    }
+
+    impl Ram {
+        fn new(writes_left: usize) -> Ram {
+            Ram {
+                words: [0; SIZE / 4],
+                busy: false,
+                writes_left,
+                lost: false,
+                written: 0,
+            }
+        }
+    }
+
+    impl Storage for Ram {
+        type Error = ();
+
+        fn read_word(&self, offset: usize) -> u32 {
+            self.words[offset / 4]
+        }
+
+        fn write_word(&mut self, offset: usize, word: u32) -> nb::Result<(), ()> {
+            self.busy = !self.busy;
+            if self.busy {
+                return Err(nb::Error::WouldBlock);
+            }
+            if self.writes_left == 0 {
+                self.lost = true;
+            } else {
+                self.writes_left -= 1;
+                self.written += 1;
+                self.words[offset / 4] = word;
+            }
+            Ok(())
+        }
+    }
+
+    fn append(log: &mut RecordLog, ram: &mut Ram, key: usize, data: &[u32]) {
+        loop {
+            match log.append(ram, key, data) {
+                Ok(()) => return,
+                Err(nb::Error::WouldBlock) => {}
+                Err(nb::Error::Other(_)) => panic!("append failed"),
+            }
+        }
+    }
+
+    /// The record of `key` in a log mounted from `ram`
+    fn mounted(ram: &Ram, key: usize) -> Option<[u32; 3]> {
+        let mut log = RecordLog::new(0, SIZE);
+        log.mount(ram);
+        let mut data = [0; 3];
+        log.read(ram, key, &mut data).map(|len| {
+            assert_eq!(len, 3);
+            data
+        })
+    }
+
+    fn record(i: u32) -> [u32; 3] {
+        [i, i << 8, !i]
+    }
+
+    #[test]
+    fn reads_back_after_mount() {
+        let mut ram = Ram::new(usize::max_value());
+        let mut log = RecordLog::new(0, SIZE);
+        log.mount(&ram);
+        assert!(mounted(&ram, 0).is_none());
+
+        append(&mut log, &mut ram, 0, &record(1));
+        append(&mut log, &mut ram, 2, &record(2));
+        append(&mut log, &mut ram, 0, &record(3));
+        assert_eq!(mounted(&ram, 0), Some(record(3)));
+        assert_eq!(mounted(&ram, 1), None);
+        assert_eq!(mounted(&ram, 2), Some(record(2)));
+
+        let mut long = [0; RECORD_WORDS + 1];
+        assert!(log.append(&mut ram, 0, &long[..]).is_err());
+        assert!(log.append(&mut ram, RECORD_KEYS, &long[..1]).is_err());
+        long[0] = 1;
+        assert_eq!(log.read(&ram, 0, &mut long[..2]), None);
+    }
+
+    #[test]
+    fn power_loss_within_a_record() {
+        for writes in 0..=5 {
+            let mut ram = Ram::new(usize::max_value());
+            let mut log = RecordLog::new(0, SIZE);
+            append(&mut log, &mut ram, 1, &record(1));
+            ram.writes_left = writes;
+            append(&mut log, &mut ram, 1, &record(2));
+
+            // the header is written last, after the data and the CRC
+            let expected = if writes < 5 { record(1) } else { record(2) };
+            assert_eq!(mounted(&ram, 1), Some(expected), "{} writes", writes);
+        }
+    }
+
+    #[test]
+    fn power_loss_leaves_no_stale_records_behind() {
+        let mut ram = Ram::new(usize::max_value());
+        let mut log = RecordLog::new(0, SIZE);
+        append(&mut log, &mut ram, 1, &[1]);
+        for i in 0..5 {
+            append(&mut log, &mut ram, 0, &record(i));
+        }
+        assert_eq!(log.head, 30);
+
+        // power is lost moving to the other sector, right after the
+        // record of key 2 was appended there behind those of keys 0
+        // and 1
+        while !matches!(
+            log.compaction,
+            Some(Compaction {
+                stage: Stage::Generation,
+                ..
+            })
+        ) {
+            let result = log.append(&mut ram, 2, &record(5));
+            assert!(matches!(result, Err(nb::Error::WouldBlock)));
+        }
+
+        // the next move stops right where that record starts
+        let mut log = RecordLog::new(0, SIZE);
+        log.mount(&ram);
+        append(&mut log, &mut ram, 1, &[2]);
+        assert_eq!(log.head, 10);
+        assert_eq!(mounted(&ram, 0), Some(record(4)));
+        assert_eq!(mounted(&ram, 2), None);
+    }
+
+    /// Append records for three keys, enough to move to the other
+    /// sector a few times, with power lost after `writes` writes.
+    /// Returns the stages of moving that were passed through, and the
+    /// number of writes made.
+    fn run(writes: usize) -> ([bool; 5], usize) {
+        let mut ram = Ram::new(writes);
+        let mut log = RecordLog::new(0, SIZE);
+        let mut stages = [false; 5];
+        let mut saved = [None; 3];
+        for i in 0..24 {
+            let key = i as usize % 3;
+            let data = record(i);
+            loop {
+                // the step of moving this append is about to take
+                let stage = match log.compaction {
+                    Some(compaction) => Some(compaction.stage),
+                    None if log.head.saturating_add(data.len() + 2) > log.sector_words => {
+                        Some(Stage::Clear(0))
+                    }
+                    None => None,
+                };
+                match stage {
+                    Some(Stage::Clear(_)) => stages[0] = true,
+                    Some(Stage::Copy(_)) => stages[1] = true,
+                    Some(Stage::Append) => stages[2] = true,
+                    Some(Stage::Generation) => stages[3] = true,
+                    Some(Stage::Magic) => stages[4] = true,
+                    None => {}
+                }
+                match log.append(&mut ram, key, &data) {
+                    Ok(()) => break,
+                    Err(nb::Error::WouldBlock) => {}
+                    Err(nb::Error::Other(_)) => panic!("append failed"),
+                }
+            }
+
+            if ram.lost {
+                // either the record before or the one being appended
+                let found = mounted(&ram, key);
+                assert!(
+                    found == saved[key] || found == Some(data),
+                    "{} writes",
+                    writes
+                );
+                for other in (0..3).filter(|&other| other != key) {
+                    assert_eq!(mounted(&ram, other), saved[other], "{} writes", writes);
+                }
+                // and the log carries on
+                let mut log = RecordLog::new(0, SIZE);
+                log.mount(&ram);
+                ram.writes_left = usize::max_value();
+                append(&mut log, &mut ram, key, &record(99));
+                assert_eq!(mounted(&ram, key), Some(record(99)));
+                return (stages, ram.written);
+            }
+            saved[key] = Some(data);
+        }
+        for (key, &data) in saved.iter().enumerate() {
+            assert_eq!(mounted(&ram, key), data);
+        }
+        (stages, ram.written)
+    }
+
+    #[test]
+    fn power_loss_at_every_write() {
+        let (stages, written) = run(usize::max_value());
+        assert_eq!(stages, [true; 5]);
+        for writes in 0..written {
+            run(writes);
+        }
+    }
+}
@@ -0,0 +1,178 @@
+use crate::eeprom::{Eeprom, EepromError, SETTINGS_LOG, SETTINGS_LOG_SIZE};
+use crate::event::elapsed;
+use crate::layout::SETTINGS_SAVE_DELAY;
+use crate::led::LedConfig;
+use crate::record_log::{LogError, RecordLog};
+
+/// Key of the settings record in the log
+const SETTINGS_RECORD: usize = 0;
+/// Marks saved settings in their first word, along with the version
+/// in the low byte
+const SETTINGS_MAGIC: u32 = 0x5354_0000;
+/// Bumped whenever the meaning of the saved words changes, so that
+/// settings saved by older firmware are not misread
+const SETTINGS_VERSION: u32 = 2;
+/// The magic and the settings
+const SETTINGS_WORDS: usize = 3;
+
+const FLAG_LED_ON: u32 = 1 << 0;
+const FLAG_SEND_USB_REPORT: u32 = 1 << 1;
+const FLAG_LED_CONFIG: u32 = 1 << 2;
+
+/// Runtime settings kept across resets
+#[derive(Copy, Clone, PartialEq)]
+pub struct Settings {
+    pub led_on: bool,
+    /// Theme, brightness and animation speed, once the LED chip
+    /// reported them
+    pub led: Option<LedConfig>,
+    pub send_usb_report: bool,
+    /// Bluetooth host last connected to, 0 if none
+    pub bluetooth_host: u8,
+}
+
+impl Settings {
+    fn to_words(self) -> [u32; SETTINGS_WORDS] {
+        let mut flags = 0;
+        if self.led_on {
+            flags |= FLAG_LED_ON;
+        }
+        if self.send_usb_report {
+            flags |= FLAG_SEND_USB_REPORT;
+        }
+        let mut led = 0;
+        if let Some(config) = self.led {
+            flags |= FLAG_LED_CONFIG;
+            led = u32::from(config.theme)
+                | u32::from(config.brightness) << 8
+                | u32::from(config.animation_speed) << 16;
+        }
+        [
+            SETTINGS_MAGIC | SETTINGS_VERSION,
+            flags | led << 8,
+            u32::from(self.bluetooth_host),
+        ]
+    }
+
+    /// The settings in `words`, if they are of this version
+    fn from_words(words: &[u32]) -> Option<Settings> {
+        if words.len() != SETTINGS_WORDS || words[0] != SETTINGS_MAGIC | SETTINGS_VERSION {
+            return None;
+        }
+        let flags = words[1];
+        let led = if flags & FLAG_LED_CONFIG != 0 {
+            Some(LedConfig {
+                theme: (words[1] >> 8) as u8,
+                brightness: (words[1] >> 16) as u8,
+                animation_speed: (words[1] >> 24) as u8,
+            })
+        } else {
+            None
+        };
+        Some(Settings {
+            led_on: flags & FLAG_LED_ON != 0,
+            led,
+            send_usb_report: flags & FLAG_SEND_USB_REPORT != 0,
+            bluetooth_host: words[2] as u8,
+        })
+    }
+}
+
+/// Keeps [`Settings`] in the data EEPROM.
+///
+/// The settings are a versioned record in a [`RecordLog`], which
+/// checks them with a CRC and spreads the writes over its sectors.
+/// Changes are saved only once the settings have stayed the same for
+/// `layout::SETTINGS_SAVE_DELAY`, so that flipping through themes
+/// writes the EEPROM once.
+pub struct SettingsStore {
+    settings: Option<Settings>,
+    /// The settings in the EEPROM
+    saved: Option<Settings>,
+    /// When the settings last changed, if they have yet to be saved
+    changed: Option<u32>,
+    /// Settings being appended to the log
+    saving: Option<Settings>,
+    log: RecordLog,
+}
+
+impl SettingsStore {
+    pub const fn new() -> SettingsStore {
+        SettingsStore {
+            settings: None,
+            saved: None,
+            changed: None,
+            saving: None,
+            log: RecordLog::new(SETTINGS_LOG, SETTINGS_LOG_SIZE),
+        }
+    }
+
+    /// The current settings, `None` before any were loaded or set
+    pub fn get(&self) -> Option<&Settings> {
+        self.settings.as_ref()
+    }
+
+    /// Take over `settings` at `now`, to be saved once they stop
+    /// changing.
+    pub fn set(&mut self, settings: Settings, now: u32) {
+        if self.settings != Some(settings) {
+            self.settings = Some(settings);
+            self.changed = Some(now);
+        }
+    }
+
+    /// Restore the settings saved in `eeprom`, if there are any of
+    /// this version.
+    pub fn load(&mut self, eeprom: &Eeprom) {
+        self.log.mount(eeprom);
+        let mut words = [0; SETTINGS_WORDS];
+        let settings = self
+            .log
+            .read(eeprom, SETTINGS_RECORD, &mut words)
+            .and_then(|len| Settings::from_words(&words[..len]));
+        if settings.is_some() {
+            self.settings = settings;
+            self.saved = settings;
+        }
+    }
+
+    /// Write the next word of a pending save to `eeprom`, starting a
+    /// save if the settings have settled by `now`.
+    pub fn save(&mut self, eeprom: &mut Eeprom, now: u32) -> nb::Result<(), EepromError> {
+        if self.saving.is_none() {
+            match self.changed {
+                Some(since) if elapsed(now, since) >= SETTINGS_SAVE_DELAY => {
+                    self.changed = None;
+                    if self.settings != self.saved {
+                        self.saving = self.settings;
+                    }
+                }
+                _ => {}
+            }
+        }
+        let settings = match self.saving {
+            Some(settings) => settings,
+            None => return Ok(()),
+        };
+
+        let result = self
+            .log
+            .append(eeprom, SETTINGS_RECORD, &settings.to_words());
+        match result {
+            Err(nb::Error::WouldBlock) => Err(nb::Error::WouldBlock),
+            Ok(()) => {
+                self.saving = None;
+                self.saved = Some(settings);
+                Ok(())
+            }
+            // give up rather than retrying forever
+            Err(nb::Error::Other(e)) => {
+                self.saving = None;
+                match e {
+                    LogError::Storage(e) => Err(nb::Error::Other(e)),
+                    LogError::OutOfRange => Err(nb::Error::Other(EepromError::OutOfRange)),
+                }
+            }
+        }
+    }
+}
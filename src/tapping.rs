@@ -0,0 +1,413 @@
+use crate::action::Action;
+use crate::event::{elapsed, EventQueue};
+use crate::keycodes::{KeyCode, MOD_LSHIFT};
+use crate::keymatrix::KeyState;
+use bit_field::BitArray;
+
+/// When a tap-hold key that is still down counts as held, besides
+/// being held past the tapping term
+#[allow(dead_code)]
+#[derive(Copy, Clone, PartialEq)]
+pub enum HoldMode {
+    /// Only the tapping term decides
+    TermOnly,
+    /// Another key pressed and released while the tap-hold key is
+    /// down makes it a hold
+    PermissiveHold,
+    /// Any other key pressed while the tap-hold key is down makes it
+    /// a hold
+    HoldOnOtherKeyPress,
+}
+
+pub struct TappingConfig {
+    /// Milliseconds a tap-hold key must be held down to act as hold
+    pub term: u32,
+    pub mode: HoldMode,
+    /// Milliseconds after which a tapped one-shot layer switches off
+    /// if no other key was pressed
+    pub one_shot_timeout: u32,
+}
+
+/// Which keys send their shifted form when held, see
+/// `Tapping::auto_shift`
+pub struct AutoShiftConfig {
+    /// Whether auto-shift is on after reset. `Action::AutoShiftToggle`
+    /// switches it at runtime.
+    pub enabled: bool,
+    /// Milliseconds a key must be held down to be shifted
+    pub timeout: u32,
+    pub letters: bool,
+    /// Digits of the number row
+    pub digits: bool,
+    /// Punctuation from `-` to `/`
+    pub symbols: bool,
+}
+
+impl AutoShiftConfig {
+    /// Whether pressing `action` waits for an auto-shift decision
+    pub fn applies(&self, action: Action) -> bool {
+        match action {
+            Action::Key(code) => {
+                (self.letters && code.is_letter())
+                    || (self.digits && code >= KeyCode::N1 && code <= KeyCode::N0)
+                    || (self.symbols && code.is_symbol())
+            }
+            _ => false,
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq)]
+pub enum TapHold {
+    Tap,
+    Hold,
+}
+
+/// A key that does different things depending on how often it is
+/// tapped in a row, see `Action::TapDance`
+pub struct TapDance {
+    /// What one, two, ... taps send
+    pub tap: &'static [Action],
+    /// What holding down the final tap does, by the number of taps.
+    /// Falls back to `tap` where missing.
+    pub hold: &'static [Action],
+}
+
+impl TapDance {
+    fn action(&self, taps: usize, decision: TapHold) -> Action {
+        let actions = match decision {
+            TapHold::Hold if taps <= self.hold.len() => self.hold,
+            _ => self.tap,
+        };
+        match actions.get(taps - 1).or_else(|| actions.last()) {
+            Some(&action) => action,
+            None => Action::Nop,
+        }
+    }
+}
+
+/// Resolves dual-role keys and tap dances into the action they
+/// stand for.
+///
+/// Key events are queued here first. Whenever the oldest event is
+/// the press of a tap-hold key, it and all later events are held
+/// back until `decide` can tell whether it was tapped or held.
+pub struct Tapping {
+    pub queue: EventQueue,
+}
+
+impl Tapping {
+    pub const fn new() -> Tapping {
+        Tapping {
+            queue: EventQueue::new(),
+        }
+    }
+
+    /// Decide what the tap-hold `action` pressed at the head of the
+    /// queue stands for, or `None` if that is not known yet.
+    pub fn decide(
+        &mut self,
+        action: Action,
+        config: &TappingConfig,
+        dances: &[TapDance],
+        now: u32,
+    ) -> Option<Action> {
+        if let Action::TapDance(index) = action {
+            return match dances.get(index as usize) {
+                Some(dance) => self.decide_dance(dance, config, now),
+                None => Some(Action::Nop),
+            };
+        }
+
+        let mode = match action {
+            // Typing quickly rolls from a dual-role layer key such as
+            // Space into the next key, which must not switch layers.
+            Action::LayerTap(_, _) if config.mode == HoldMode::HoldOnOtherKeyPress => {
+                HoldMode::PermissiveHold
+            }
+            _ => config.mode,
+        };
+        let decision = self.tap_or_hold(mode, config.term, now)?;
+        Some(match (action, decision) {
+            (Action::ModTap(modifier, _), TapHold::Hold) => Action::Key(modifier),
+            (Action::LayerTap(layer, _), TapHold::Hold) => Action::LayerMomentary(layer),
+            (Action::ModTap(_, tap), TapHold::Tap) | (Action::LayerTap(_, tap), TapHold::Tap) => {
+                Action::Key(tap)
+            }
+            _ => action,
+        })
+    }
+
+    /// Decide whether the key `action` pressed at the head of the
+    /// queue is sent shifted, as it is held for the auto-shift
+    /// timeout. Only its own release is waited for, so typing can
+    /// roll over into the next keys.
+    pub fn auto_shift(&self, action: Action, config: &AutoShiftConfig, now: u32) -> Option<Action> {
+        let decision = self.tap_or_hold(HoldMode::TermOnly, config.timeout, now)?;
+        Some(match (action, decision) {
+            (Action::Key(code), TapHold::Hold) => Action::KeyWithMods(MOD_LSHIFT, code),
+            _ => action,
+        })
+    }
+
+    /// Decide whether the key pressed at the head of the queue is
+    /// tapped or held for `term` milliseconds.
+    fn tap_or_hold(&self, mode: HoldMode, term: u32, now: u32) -> Option<TapHold> {
+        let press = self.queue.peek()?;
+        // keys pressed after the tap-hold key went down
+        let mut others: KeyState = [0; 9];
+
+        for event in self.queue.iter().skip(1) {
+            if event.key == press.key {
+                return if elapsed(event.time, press.time) < term {
+                    Some(TapHold::Tap)
+                } else {
+                    Some(TapHold::Hold)
+                };
+            }
+            match (mode, event.pressed) {
+                (HoldMode::HoldOnOtherKeyPress, true) => return Some(TapHold::Hold),
+                (_, true) => others.set_bit(event.key as usize, true),
+                (HoldMode::PermissiveHold, false) if others.get_bit(event.key as usize) => {
+                    return Some(TapHold::Hold)
+                }
+                _ => {}
+            }
+        }
+
+        // Nothing more can be buffered, so stop waiting
+        if self.queue.is_full() || elapsed(now, press.time) >= term {
+            Some(TapHold::Hold)
+        } else {
+            None
+        }
+    }
+
+    /// Count the taps of the tap dance pressed at the head of the
+    /// queue, and pick its action once the dance is over.
+    ///
+    /// The dance is over when the key stays released or held for the
+    /// tapping term, or another key is pressed, which counts as
+    /// holding the final tap if the key is still down. All but the final
+    /// press and release of the key are then dropped from the queue,
+    /// so the head press stands for the whole dance.
+    fn decide_dance(
+        &mut self,
+        dance: &TapDance,
+        config: &TappingConfig,
+        now: u32,
+    ) -> Option<Action> {
+        let press = self.queue.peek()?;
+        let mut taps = 1;
+        let mut down = true;
+        // time of the latest press or release of the dancing key
+        let mut last = press.time;
+        let mut decision = None;
+
+        for event in self.queue.iter().skip(1) {
+            if event.key == press.key {
+                if elapsed(event.time, last) >= config.term {
+                    // held too long, or waited too long for the next tap
+                    decision = Some(if down { TapHold::Hold } else { TapHold::Tap });
+                    break;
+                }
+                if event.pressed {
+                    taps += 1;
+                }
+                down = event.pressed;
+                last = event.time;
+            } else if event.pressed {
+                decision = Some(if down { TapHold::Hold } else { TapHold::Tap });
+                break;
+            }
+        }
+
+        let waited = self.queue.is_full() || elapsed(now, last) >= config.term;
+        // another tap would not change the outcome
+        let last_tap = taps >= dance.tap.len() && taps >= dance.hold.len();
+        let decision = match decision {
+            Some(decision) => decision,
+            None if down && waited => TapHold::Hold,
+            None if !down && (waited || last_tap) => TapHold::Tap,
+            None => return None,
+        };
+
+        // Drop the presses and releases of all taps but the last,
+        // which leaves the head press in place
+        let mut dropped = 0;
+        let mut i = 1;
+        while dropped < 2 * (taps - 1) {
+            match self.queue.get(i) {
+                Some(event) if event.key == press.key => {
+                    self.queue.remove(i);
+                    dropped += 1;
+                }
+                Some(_) => i += 1,
+                None => break,
+            }
+        }
+
+        Some(dance.action(taps, decision))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::event::{KeyEvent, QUEUE_SIZE};
+
+    const TAPPING: TappingConfig = TappingConfig {
+        term: 200,
+        mode: HoldMode::TermOnly,
+        one_shot_timeout: 1000,
+    };
+    const MOD_TAP: Action = Action::ModTap(KeyCode::LCtrl, KeyCode::Escape);
+    const LAYER_TAP: Action = Action::LayerTap(1, KeyCode::Space);
+    const DANCES: [TapDance; 1] = [TapDance {
+        tap: &[Action::Key(KeyCode::A), Action::Key(KeyCode::B)],
+        hold: &[Action::Key(KeyCode::LShift)],
+    }];
+
+    /// Queue the presses (`true`) and releases of keys at the given
+    /// milliseconds, as the matrix scan would
+    fn script(timeline: &[(u32, u8, bool)]) -> Tapping {
+        let mut tapping = Tapping::new();
+        for &(time, key, pressed) in timeline {
+            let event = KeyEvent { key, pressed, time };
+            assert!(tapping.queue.push(event).is_ok());
+        }
+        tapping
+    }
+
+    fn with_mode(mode: HoldMode) -> TappingConfig {
+        TappingConfig { mode, ..TAPPING }
+    }
+
+    #[test]
+    fn tapped_within_the_term() {
+        let mut tapping = script(&[(0, 0, true), (50, 0, false)]);
+        let decision = tapping.decide(MOD_TAP, &TAPPING, &DANCES, 60);
+        assert!(decision == Some(Action::Key(KeyCode::Escape)));
+    }
+
+    #[test]
+    fn held_past_the_term() {
+        let mut tapping = script(&[(0, 0, true)]);
+        assert!(tapping.decide(MOD_TAP, &TAPPING, &DANCES, 199).is_none());
+        let decision = tapping.decide(MOD_TAP, &TAPPING, &DANCES, 200);
+        assert!(decision == Some(Action::Key(KeyCode::LCtrl)));
+    }
+
+    #[test]
+    fn released_after_the_term() {
+        let mut tapping = script(&[(0, 0, true), (250, 0, false)]);
+        let decision = tapping.decide(LAYER_TAP, &TAPPING, &DANCES, 250);
+        assert!(decision == Some(Action::LayerMomentary(1)));
+    }
+
+    #[test]
+    fn term_only_waits_for_the_term() {
+        let mut tapping = script(&[(0, 0, true), (20, 1, true), (40, 1, false)]);
+        assert!(tapping.decide(MOD_TAP, &TAPPING, &DANCES, 50).is_none());
+    }
+
+    #[test]
+    fn permissive_hold() {
+        let config = with_mode(HoldMode::PermissiveHold);
+        // rolling over into the next key is still a tap
+        let mut tapping = script(&[(0, 0, true), (20, 1, true), (40, 0, false)]);
+        let decision = tapping.decide(MOD_TAP, &config, &DANCES, 50);
+        assert!(decision == Some(Action::Key(KeyCode::Escape)));
+
+        // a key pressed and released within is modified
+        let mut tapping = script(&[(0, 0, true), (20, 1, true), (40, 1, false)]);
+        let decision = tapping.decide(MOD_TAP, &config, &DANCES, 50);
+        assert!(decision == Some(Action::Key(KeyCode::LCtrl)));
+
+        // releasing a key pressed before does not count
+        let mut tapping = script(&[(0, 0, true), (20, 1, false)]);
+        assert!(tapping.decide(MOD_TAP, &config, &DANCES, 50).is_none());
+    }
+
+    #[test]
+    fn hold_on_other_key_press() {
+        let config = with_mode(HoldMode::HoldOnOtherKeyPress);
+        let mut tapping = script(&[(0, 0, true), (20, 1, true)]);
+        let decision = tapping.decide(MOD_TAP, &config, &DANCES, 30);
+        assert!(decision == Some(Action::Key(KeyCode::LCtrl)));
+
+        // but layer keys roll over
+        let mut tapping = script(&[(0, 0, true), (20, 1, true)]);
+        assert!(tapping.decide(LAYER_TAP, &config, &DANCES, 30).is_none());
+    }
+
+    #[test]
+    fn full_queue_stops_waiting() {
+        let mut timeline = [(0, 0, true); QUEUE_SIZE];
+        for (i, event) in timeline.iter_mut().enumerate().skip(1) {
+            *event = (i as u32, 1, i % 2 == 1);
+        }
+        let mut tapping = script(&timeline);
+        let decision = tapping.decide(MOD_TAP, &TAPPING, &DANCES, 20);
+        assert!(decision == Some(Action::Key(KeyCode::LCtrl)));
+    }
+
+    #[test]
+    fn auto_shift() {
+        let config = AutoShiftConfig {
+            enabled: true,
+            timeout: 150,
+            letters: true,
+            digits: false,
+            symbols: false,
+        };
+        let key = Action::Key(KeyCode::A);
+        assert!(config.applies(key));
+        assert!(!config.applies(Action::Key(KeyCode::N1)));
+
+        let tapping = script(&[(0, 0, true), (100, 0, false)]);
+        assert!(tapping.auto_shift(key, &config, 100) == Some(key));
+
+        let tapping = script(&[(0, 0, true), (20, 1, true)]);
+        assert!(tapping.auto_shift(key, &config, 100).is_none());
+        let shifted = Action::KeyWithMods(MOD_LSHIFT, KeyCode::A);
+        assert!(tapping.auto_shift(key, &config, 150) == Some(shifted));
+    }
+
+    #[test]
+    fn tap_dance_counts_taps() {
+        let dance = Action::TapDance(0);
+        // a single tap waits for another one
+        let mut tapping = script(&[(0, 0, true), (30, 0, false)]);
+        assert!(tapping.decide(dance, &TAPPING, &DANCES, 100).is_none());
+        let decision = tapping.decide(dance, &TAPPING, &DANCES, 230);
+        assert!(decision == Some(Action::Key(KeyCode::A)));
+
+        // the second tap is the last one with an action
+        let timeline = [(0, 0, true), (30, 0, false), (60, 0, true), (90, 0, false)];
+        let mut tapping = script(&timeline);
+        let decision = tapping.decide(dance, &TAPPING, &DANCES, 90);
+        assert!(decision == Some(Action::Key(KeyCode::B)));
+        // leaving the final press and release
+        assert_eq!(tapping.queue.iter().count(), 2);
+        assert!(tapping.queue.get(1).map(|event| event.time) == Some(90));
+    }
+
+    #[test]
+    fn tap_dance_held_or_interrupted() {
+        let dance = Action::TapDance(0);
+        let mut tapping = script(&[(0, 0, true)]);
+        let decision = tapping.decide(dance, &TAPPING, &DANCES, 200);
+        assert!(decision == Some(Action::Key(KeyCode::LShift)));
+
+        // another key ends the dance, tapped
+        let mut tapping = script(&[(0, 0, true), (30, 0, false), (60, 1, true)]);
+        let decision = tapping.decide(dance, &TAPPING, &DANCES, 60);
+        assert!(decision == Some(Action::Key(KeyCode::A)));
+
+        // unknown dances do nothing
+        let mut tapping = script(&[(0, 0, true)]);
+        let decision = tapping.decide(Action::TapDance(1), &TAPPING, &DANCES, 0);
+        assert!(decision == Some(Action::Nop));
+    }
+}
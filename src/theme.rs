@@ -1,4 +1,6 @@
+use crate::action::Action;
 use crate::bluetooth::BluetoothMode;
+use crate::keycodes::KeyIndex;
 use crate::layout::Layout;
 
 pub struct LedTheme {
@@ -40,6 +42,7 @@ pub fn layout_to_theme(
     bt_connected_host: u8,
     bt_mode: BluetoothMode,
     keyboard_send_usb_report: bool,
+    caps_word: bool,
 ) -> LedTheme {
     let mut theme = LedTheme::new();
     for (index, action) in layout.iter().enumerate() {
@@ -48,6 +51,16 @@ pub fn layout_to_theme(
             bt_connected_host,
             bt_mode,
             keyboard_send_usb_report,
+            caps_word,
+        );
+    }
+    if caps_word {
+        theme.key_colors[KeyIndex::Capslock as usize] = Action::CapsWord.to_color(
+            bt_saved_hosts,
+            bt_connected_host,
+            bt_mode,
+            keyboard_send_usb_report,
+            caps_word,
         );
     }
     theme
@@ -1,28 +1,228 @@
+use crate::hidreport::MouseReport;
+use crate::layout::NKRO;
 use crate::usb::pma::PMA;
 use crate::usb::usb_ext::UsbEpExt;
 use stm32l1::stm32l151::USB;
 
+/// HID protocol selected by the host through SET_PROTOCOL, the
+/// other one being the boot protocol (0)
+pub const REPORT_PROTOCOL: u8 = 1;
+
+/// Report IDs on the extra interface, see
+/// [`descriptors::EXTRA_REPORT_DESC`]
+const CONSUMER_REPORT_ID: u8 = 1;
+const SYSTEM_REPORT_ID: u8 = 2;
+const MOUSE_REPORT_ID: u8 = 3;
+
 pub struct UsbHid {
     pub report: [u8; 8],
+    pub nkro_report: [u8; 29],
     pub protocol: u8,
+    /// Send keys through the NKRO interface while the host uses the
+    /// report protocol
+    pub nkro: bool,
+    /// `report` changed since it was last put on the endpoint
+    report_queued: bool,
+    /// The report on the endpoint is the latest one, and the host has
+    /// yet to pick it up
+    report_loaded: bool,
+    /// Consumer control usage currently pressed
+    pub consumer: u16,
+    /// Whether the host has yet to see the current `consumer`
+    consumer_pending: bool,
+    /// System control usage currently pressed
+    pub system: u8,
+    system_pending: bool,
+    /// Mouse movement yet to be sent
+    mouse: MouseReport,
+    mouse_pending: bool,
+    /// Whether a report is waiting on the extra endpoint
+    extra_busy: bool,
 }
 
 impl UsbHid {
     pub fn new() -> UsbHid {
         UsbHid {
             report: [0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00],
-            protocol: 0,
+            nkro_report: [0; 29],
+            protocol: REPORT_PROTOCOL,
+            nkro: NKRO,
+            report_queued: false,
+            report_loaded: false,
+            consumer: 0,
+            consumer_pending: false,
+            system: 0,
+            system_pending: false,
+            mouse: MouseReport::default(),
+            mouse_pending: false,
+            extra_busy: false,
+        }
+    }
+
+    /// Whether keys are sent in the NKRO report instead of the boot
+    /// report
+    pub fn nkro_active(&self) -> bool {
+        self.nkro && self.protocol == REPORT_PROTOCOL
+    }
+
+    /// Note that `report` or `nkro_report` changed.
+    pub fn queue_report(&mut self) {
+        self.report_queued = true;
+    }
+
+    /// Whether the host picked up the latest keyboard report
+    pub fn report_sent(&self) -> bool {
+        !self.report_queued && !self.report_loaded
+    }
+
+    /// Forget the keyboard report on the endpoint, as the host does
+    /// on a bus reset.
+    pub fn reset_report(&mut self) {
+        self.report_queued = false;
+        self.report_loaded = false;
+    }
+
+    /// The host picked up the report on the endpoint the keys are sent
+    /// on, which is about to be replaced by the latest one.
+    fn report_polled(&mut self) {
+        self.report_loaded = self.report_queued;
+        self.report_queued = false;
+    }
+
+    /// The boot report to send, empty while NKRO is active
+    pub fn boot_report(&self) -> [u8; 8] {
+        if self.nkro_active() {
+            [0; 8]
+        } else {
+            self.report
+        }
+    }
+
+    /// The NKRO report to send, empty while the boot report is used
+    pub fn nkro_report(&self) -> [u8; 29] {
+        if self.nkro_active() {
+            self.nkro_report
+        } else {
+            [0; 29]
         }
     }
 
     pub fn ctr(&mut self, usb: &mut USB, pma: &mut PMA) {
         if !usb.istr.read().dir().bit_is_set() {
-            pma.write_buffer_u8(0x100, &self.report);
-            pma.pma_area.set_u16(10, self.report.len() as u16);
+            if !self.nkro_active() {
+                self.report_polled();
+            }
+            let report = self.boot_report();
+            pma.write_buffer_u8(0x100, &report);
+            pma.pma_area.set_u16(10, report.len() as u16);
             usb.usb_ep1r.toggle_tx_out();
         //TODO: stall?
         } else {
             panic!()
         }
     }
+
+    pub fn nkro_ctr(&mut self, usb: &mut USB, pma: &mut PMA) {
+        if !usb.istr.read().dir().bit_is_set() {
+            if self.nkro_active() {
+                self.report_polled();
+            }
+            let report = self.nkro_report();
+            pma.write_buffer_u8(0x140, &report);
+            pma.pma_area.set_u16(18, report.len() as u16);
+            usb.usb_ep2r.toggle_tx_out();
+        } else {
+            panic!()
+        }
+    }
+
+    pub fn update_consumer(&mut self, usb: &mut USB, pma: &mut PMA, consumer: u16) {
+        if consumer != self.consumer {
+            self.consumer = consumer;
+            self.consumer_pending = true;
+            if !self.extra_busy {
+                self.send_extra(usb, pma);
+            }
+        }
+    }
+
+    pub fn update_system(&mut self, usb: &mut USB, pma: &mut PMA, system: u8) {
+        if system != self.system {
+            self.system = system;
+            self.system_pending = true;
+            if !self.extra_busy {
+                self.send_extra(usb, pma);
+            }
+        }
+    }
+
+    /// Queue `report`, adding its movement to that of a report still
+    /// waiting to be sent.
+    pub fn update_mouse(&mut self, usb: &mut USB, pma: &mut PMA, report: &MouseReport) {
+        if self.mouse_pending {
+            self.mouse.buttons = report.buttons;
+            self.mouse.x = self.mouse.x.saturating_add(report.x);
+            self.mouse.y = self.mouse.y.saturating_add(report.y);
+            self.mouse.wheel = self.mouse.wheel.saturating_add(report.wheel);
+        } else {
+            self.mouse = *report;
+            self.mouse_pending = true;
+        }
+        if !self.extra_busy {
+            self.send_extra(usb, pma);
+        }
+    }
+
+    /// Forget the extra reports, as the host does on a bus reset.
+    pub fn reset_extra(&mut self) {
+        self.consumer = 0;
+        self.consumer_pending = false;
+        self.system = 0;
+        self.system_pending = false;
+        self.mouse_pending = false;
+        self.extra_busy = false;
+    }
+
+    /// Unlike the keyboard reports, which are sent on every poll, the
+    /// extra reports share an endpoint and are only sent when they
+    /// change.
+    pub fn extra_ctr(&mut self, usb: &mut USB, pma: &mut PMA) {
+        if !usb.istr.read().dir().bit_is_set() {
+            self.extra_busy = false;
+            // clear CTR_TX, leaving the endpoint NAKing
+            usb.usb_ep3r.toggle(0, 0, 0);
+            self.send_extra(usb, pma);
+        } else {
+            panic!()
+        }
+    }
+
+    fn send_extra(&mut self, usb: &mut USB, pma: &mut PMA) {
+        if self.consumer_pending {
+            let [low, high] = self.consumer.to_le_bytes();
+            self.send_extra_report(usb, pma, &[CONSUMER_REPORT_ID, low, high]);
+            self.consumer_pending = false;
+        } else if self.system_pending {
+            self.send_extra_report(usb, pma, &[SYSTEM_REPORT_ID, self.system]);
+            self.system_pending = false;
+        } else if self.mouse_pending {
+            let mouse = self.mouse;
+            let report = [
+                MOUSE_REPORT_ID,
+                mouse.buttons,
+                mouse.x as u8,
+                mouse.y as u8,
+                mouse.wheel as u8,
+            ];
+            self.send_extra_report(usb, pma, &report);
+            self.mouse_pending = false;
+        }
+    }
+
+    fn send_extra_report(&mut self, usb: &mut USB, pma: &mut PMA, report: &[u8]) {
+        pma.write_buffer_u8(0x160, report);
+        pma.pma_area.set_u16(26, report.len() as u16);
+        usb.usb_ep3r.toggle_tx_out();
+        self.extra_busy = true;
+    }
 }
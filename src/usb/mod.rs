@@ -11,10 +11,17 @@ use stm32l1::stm32l151;
 use self::constants::{UsbDescriptorType, UsbDeviceState, UsbRequest};
 use self::pma::PMA;
 use self::usb_ext::UsbEpExt;
-use crate::hidreport::HidReport;
-use crate::usb::hid::UsbHid;
+use crate::hidreport::{HidReport, MouseReport, NkroReport};
+use crate::usb::hid::{UsbHid, REPORT_PROTOCOL};
 
 const MAX_PACKET_SIZE: u32 = 64;
+/// PMA buffers of endpoint 0, one packet each
+const EP0_RX: usize = 0x20;
+const EP0_TX: usize = 0x60;
+/// Feature selector of SET_FEATURE for remote wakeup
+const DEVICE_REMOTE_WAKEUP: u16 = 1;
+/// Ticks to signal resume for, 1 to 15 ms as per the USB spec
+const RESUME_TICKS: u8 = 2;
 
 pub struct Usb {
     usb: stm32l151::USB,
@@ -22,6 +29,15 @@ pub struct Usb {
     pma: &'static mut PMA,
     hid: UsbHid,
     device_state: UsbDeviceState,
+    /// Whether the host allowed the device to wake it up
+    remote_wakeup: bool,
+    /// Ticks left to signal resume for
+    resume_ticks: u8,
+    /// Rest of the data of the control IN transfer under way, sent
+    /// one packet at a time as the host picks them up
+    control_in: &'static [u8],
+    /// Whether that transfer still ends with a zero-length packet
+    control_zlp: bool,
 }
 
 impl Usb {
@@ -43,8 +59,8 @@ impl Usb {
             w.ctrm().set_bit()
              .errm().set_bit()
              .pmaovrm().set_bit()
-             //.wkupm().set_bit()
-             //.suspm().set_bit()
+             .wkupm().set_bit()
+             .suspm().set_bit()
              //.esofm().set_bit()
              //.sofm().set_bit()
              .resetm().set_bit()
@@ -64,11 +80,69 @@ impl Usb {
             pma,
             hid,
             device_state: UsbDeviceState::Disconnected,
+            remote_wakeup: false,
+            resume_ticks: 0,
+            control_in: &[],
+            control_zlp: false,
         }
     }
 
-    pub fn update_report(&mut self, report: &HidReport) {
+    pub fn update_report(&mut self, report: &HidReport, nkro_report: &NkroReport) {
         self.hid.report[..].clone_from_slice(report.as_bytes());
+        self.hid.nkro_report[..].clone_from_slice(nkro_report.as_bytes());
+        self.hid.queue_report();
+    }
+
+    /// Whether the host picked up the latest report passed to
+    /// `update_report`, or does not poll for reports at all
+    pub fn report_sent(&self) -> bool {
+        match self.device_state {
+            UsbDeviceState::Configured => self.hid.report_sent(),
+            _ => true,
+        }
+    }
+
+    /// Send the consumer control `usage`, or 0 once it is released.
+    pub fn update_consumer_report(&mut self, usage: u16) {
+        self.hid
+            .update_consumer(&mut self.usb, &mut self.pma, usage);
+    }
+
+    /// Send the system control `usage`, or 0 once it is released.
+    pub fn update_system_report(&mut self, usage: u8) {
+        self.hid.update_system(&mut self.usb, &mut self.pma, usage);
+    }
+
+    pub fn update_mouse_report(&mut self, report: &MouseReport) {
+        self.hid.update_mouse(&mut self.usb, &mut self.pma, report);
+    }
+
+    /// Wake up the host if it is suspended and allowed remote wakeup.
+    pub fn wake_up(&mut self) {
+        if let UsbDeviceState::Suspended = self.device_state {
+            if self.remote_wakeup && self.resume_ticks == 0 {
+                self.usb.usb_cntr.modify(|_, w| w.fsusp().clear_bit());
+                self.usb.usb_cntr.modify(|_, w| w.resume().set_bit());
+                self.resume_ticks = RESUME_TICKS;
+            }
+        }
+    }
+
+    /// Stop signalling resume once it went on long enough.
+    pub fn tick(&mut self) {
+        if self.resume_ticks > 0 {
+            self.resume_ticks -= 1;
+            if self.resume_ticks == 0 {
+                self.usb.usb_cntr.modify(|_, w| w.resume().clear_bit());
+                self.device_state = UsbDeviceState::Configured;
+            }
+        }
+    }
+
+    /// Switch between the boot (6KRO) and the NKRO report.
+    pub fn toggle_nkro(&mut self) {
+        self.hid.nkro = !self.hid.nkro;
+        crate::heprintln!("nkro: {:?}", self.hid.nkro).ok();
     }
 
     pub fn interrupt(&mut self) {
@@ -78,9 +152,29 @@ impl Usb {
             self.reset();
         }
 
-        self.usb
-            .istr
-            .modify(|_, w| w.susp().clear_bit().sof().clear_bit().esof().clear_bit());
+        if istr.susp().bit_is_set() {
+            if let UsbDeviceState::Configured = self.device_state {
+                self.usb.usb_cntr.modify(|_, w| w.fsusp().set_bit());
+                self.device_state = UsbDeviceState::Suspended;
+            }
+        }
+        if istr.wkup().bit_is_set() {
+            self.usb.usb_cntr.modify(|_, w| w.fsusp().clear_bit());
+            if let UsbDeviceState::Suspended = self.device_state {
+                self.device_state = UsbDeviceState::Configured;
+            }
+        }
+
+        self.usb.istr.modify(|_, w| {
+            w.susp()
+                .clear_bit()
+                .wkup()
+                .clear_bit()
+                .sof()
+                .clear_bit()
+                .esof()
+                .clear_bit()
+        });
         let istr = self.usb.istr.read();
         if istr.ctr().bit_is_set() {
             self.usb.istr.modify(|_, w| w.ctr().clear_bit());
@@ -93,15 +187,21 @@ impl Usb {
                 1 => {
                     self.hid.ctr(&mut self.usb, &mut self.pma);
                 }
+                2 => {
+                    self.hid.nkro_ctr(&mut self.usb, &mut self.pma);
+                }
+                3 => {
+                    self.hid.extra_ctr(&mut self.usb, &mut self.pma);
+                }
                 _ => panic!(),
             }
         }
     }
 
     fn reset(&mut self) {
-        self.pma.pma_area.set_u16(0, 0x40);
+        self.pma.pma_area.set_u16(0, EP0_TX as u16);
         self.pma.pma_area.set_u16(2, 0x0);
-        self.pma.pma_area.set_u16(4, 0x20);
+        self.pma.pma_area.set_u16(4, EP0_RX as u16);
         self.pma
             .pma_area
             .set_u16(6, (0x8000 | ((MAX_PACKET_SIZE / 32) - 1) << 10) as u16);
@@ -110,6 +210,17 @@ impl Usb {
 
         self.pma.write_buffer_u8(0x100, &self.hid.report);
         self.pma.pma_area.set_u16(10, 5);
+        self.hid.reset_report();
+
+        self.pma.pma_area.set_u16(16, 0x140);
+        self.pma.write_buffer_u8(0x140, &self.hid.nkro_report());
+        self.pma
+            .pma_area
+            .set_u16(18, self.hid.nkro_report.len() as u16);
+
+        self.pma.pma_area.set_u16(24, 0x160);
+        self.pma.pma_area.set_u16(26, 0x0);
+        self.hid.reset_extra();
 
         self.usb.usb_ep0r.modify(|_, w| unsafe {
             w.ep_type()
@@ -131,6 +242,34 @@ impl Usb {
                 .bits(0b1)
         });
 
+        self.usb.usb_ep2r.modify(|_, w| unsafe {
+            w.ep_type()
+                .bits(0b11)
+                .stat_tx()
+                .bits(0b11)
+                .stat_rx()
+                .bits(0b10)
+                .ea()
+                .bits(0b10)
+        });
+
+        self.usb.usb_ep3r.modify(|_, w| unsafe {
+            w.ep_type()
+                .bits(0b11)
+                .stat_tx()
+                .bits(0b10)
+                .stat_rx()
+                .bits(0b10)
+                .ea()
+                .bits(0b11)
+        });
+
+        // the host selects the boot protocol again if it needs it
+        self.hid.protocol = REPORT_PROTOCOL;
+        self.remote_wakeup = false;
+        self.control_in = &[];
+        self.control_zlp = false;
+
         self.usb.daddr.write(|w| w.ef().set_bit());
 
         self.device_state = UsbDeviceState::Default;
@@ -145,6 +284,18 @@ impl Usb {
     }
 
     fn tx(&mut self) {
+        if !self.control_in.is_empty() {
+            self.send_control_packet();
+            self.usb.usb_ep0r.toggle_tx_out();
+            return;
+        }
+        if self.control_zlp {
+            self.control_zlp = false;
+            self.pma.pma_area.set_u16(2, 0);
+            self.usb.usb_ep0r.toggle_tx_out();
+            return;
+        }
+
         if self.pending_daddr != 0 {
             self.usb
                 .daddr
@@ -157,10 +308,34 @@ impl Usb {
         self.usb.usb_ep0r.toggle_tx_out();
     }
 
+    /// Answer a request for `length` bytes with `data`. Packets of
+    /// more than `MAX_PACKET_SIZE` bytes would be dropped by the host,
+    /// so the first packet is sent right away and `tx` sends the rest.
+    fn control_in(&mut self, data: &'static [u8], length: u16) {
+        let data = &data[..min(usize::from(length), data.len())];
+        // The host stops at the first short packet. Data shorter than
+        // requested, but of whole packets, ends with an empty one.
+        self.control_zlp = !data.is_empty()
+            && data.len() < usize::from(length)
+            && data.len() % MAX_PACKET_SIZE as usize == 0;
+        self.control_in = data;
+        self.send_control_packet();
+        self.usb.usb_ep0r.toggle_out();
+    }
+
+    /// Put the next packet of `control_in` into the PMA.
+    fn send_control_packet(&mut self) {
+        let size = min(self.control_in.len(), MAX_PACKET_SIZE as usize);
+        let (packet, rest) = self.control_in.split_at(size);
+        self.pma.write_buffer_u8(EP0_TX, packet);
+        self.pma.pma_area.set_u16(2, size as u16);
+        self.control_in = rest;
+    }
+
     fn get_device_descriptor(&mut self, value: u16, length: u16) {
         let descriptor_type = UsbDescriptorType::from((value >> 8) as u8);
         let index = (value & 0xff) as u8;
-        let descriptor: Option<&[u8]> = match descriptor_type {
+        let descriptor: Option<&'static [u8]> = match descriptor_type {
             UsbDescriptorType::Configuration => Some(&descriptors::CONF_DESC),
             UsbDescriptorType::Device => Some(&descriptors::DEV_DESC),
             UsbDescriptorType::DeviceQualifier => Some(&descriptors::DEVICE_QUALIFIER),
@@ -180,22 +355,19 @@ impl Usb {
             }
         };
         match descriptor {
-            Some(bytes) => {
-                self.pma.write_buffer_u8(0x40, bytes);
-                self.pma
-                    .pma_area
-                    .set_u16(2, min(length, bytes.len() as u16));
-                self.usb.usb_ep0r.toggle_out();
-            }
+            Some(bytes) => self.control_in(bytes, length),
             None => self.usb.usb_ep0r.toggle_tx_stall(),
         }
     }
 
     fn rx(&mut self) {
-        let request16 = self.pma.pma_area.get_u16(0x20);
-        let value = self.pma.pma_area.get_u16(0x22);
-        //let index = self.pma.pma_area.get_u16(0x24);
-        let length = self.pma.pma_area.get_u16(0x26);
+        let request16 = self.pma.pma_area.get_u16(EP0_RX);
+        let value = self.pma.pma_area.get_u16(EP0_RX + 2);
+        let index = self.pma.pma_area.get_u16(EP0_RX + 4);
+        let length = self.pma.pma_area.get_u16(EP0_RX + 6);
+        // a new request ends any transfer under way
+        self.control_in = &[];
+        self.control_zlp = false;
 
         self.pma
             .pma_area
@@ -208,13 +380,23 @@ impl Usb {
                 self.usb.usb_ep0r.toggle_tx_stall();
             }
             (0x80, UsbRequest::GetStatus) => {
-                self.pma.pma_area.set_u16(0x40, 0);
+                // bit 1: remote wakeup enabled
+                let status = if self.remote_wakeup { 0b10 } else { 0 };
+                self.pma.pma_area.set_u16(EP0_TX, status);
                 self.pma.pma_area.set_u16(2, 2);
                 self.usb.usb_ep0r.toggle_out();
             }
+            (0x00, UsbRequest::ClearFeature) if value == DEVICE_REMOTE_WAKEUP => {
+                self.remote_wakeup = false;
+                self.usb.usb_ep0r.toggle_0();
+            }
             (0x00, UsbRequest::ClearFeature) => {
                 self.usb.usb_ep0r.toggle_tx_stall();
             }
+            (0x00, UsbRequest::SetFeature) if value == DEVICE_REMOTE_WAKEUP => {
+                self.remote_wakeup = true;
+                self.usb.usb_ep0r.toggle_0();
+            }
             (0x00, UsbRequest::SetFeature) => {
                 self.usb.usb_ep0r.toggle_tx_stall();
             }
@@ -229,19 +411,19 @@ impl Usb {
                 self.usb.usb_ep0r.toggle_tx_stall();
             }
             (0x80, UsbRequest::GetConfiguration) => {
-                self.pma.pma_area.set_u16(0x40, 1);
+                self.pma.pma_area.set_u16(EP0_TX, 1);
                 self.pma.pma_area.set_u16(2, 1);
                 self.usb.usb_ep0r.toggle_0();
             }
             (0x00, UsbRequest::SetConfiguration) => {
-                self.pma.pma_area.set_u16(0x40, 0);
+                self.pma.pma_area.set_u16(EP0_TX, 0);
                 self.pma.pma_area.set_u16(2, 0);
                 self.usb.usb_ep0r.toggle_0();
                 self.device_state = UsbDeviceState::Configured;
             }
 
             (0x81, UsbRequest::GetStatus) => {
-                self.pma.pma_area.set_u16(0x40, 0);
+                self.pma.pma_area.set_u16(EP0_TX, 0);
                 self.pma.pma_area.set_u16(2, 2);
                 self.usb.usb_ep0r.toggle_out();
             }
@@ -252,7 +434,7 @@ impl Usb {
                 self.usb.usb_ep0r.toggle_tx_stall();
             }
             (0x01, UsbRequest::GetInterface) => {
-                self.pma.pma_area.set_u16(0x40, 0);
+                self.pma.pma_area.set_u16(EP0_TX, 0);
                 self.pma.pma_area.set_u16(2, 1);
                 self.usb.usb_ep0r.toggle_0();
             }
@@ -263,19 +445,20 @@ impl Usb {
                 let descriptor_type = UsbDescriptorType::from((value >> 8) as u8);
                 match descriptor_type {
                     UsbDescriptorType::Hid => {
-                        self.pma.write_buffer_u8(0x40, &descriptors::HID_DESC);
-                        self.pma
-                            .pma_area
-                            .set_u16(2, min(length, descriptors::HID_DESC.len() as u16));
-                        self.usb.usb_ep0r.toggle_out();
+                        let descriptor: &'static [u8] = match index {
+                            1 => &descriptors::NKRO_HID_DESC,
+                            2 => &descriptors::EXTRA_HID_DESC,
+                            _ => &descriptors::HID_DESC,
+                        };
+                        self.control_in(descriptor, length);
                     }
                     UsbDescriptorType::HidReport => {
-                        self.pma
-                            .write_buffer_u8(0x40, &descriptors::HID_REPORT_DESC);
-                        self.pma
-                            .pma_area
-                            .set_u16(2, min(length, descriptors::HID_REPORT_DESC.len() as u16));
-                        self.usb.usb_ep0r.toggle_out();
+                        let descriptor: &'static [u8] = match index {
+                            1 => &descriptors::NKRO_REPORT_DESC,
+                            2 => &descriptors::EXTRA_REPORT_DESC,
+                            _ => &descriptors::HID_REPORT_DESC,
+                        };
+                        self.control_in(descriptor, length);
                     }
                     _ => {
                         crate::heprintln!("{:x}", value).ok();
@@ -288,8 +471,11 @@ impl Usb {
                 self.usb.usb_ep0r.toggle_out();
             }
             (0x21, UsbRequest::SetInterface) => {
-                // actually hid set protocol 0xb
-                self.hid.protocol = value as u8;
+                // actually hid set protocol 0xb, only the boot
+                // interface supports the boot protocol
+                if index == 0 {
+                    self.hid.protocol = value as u8;
+                }
                 self.usb.usb_ep0r.toggle_0();
             }
             (0x21, UsbRequest::SetConfiguration) => {
@@ -299,14 +485,18 @@ impl Usb {
             }
             (0xa1, UsbRequest::SetFeature) => {
                 // this is actually hid get_protocol (3)
-                // boot protocol
-                self.pma.pma_area.set_u16(0x40, self.hid.protocol.into());
+                let protocol = if index == 0 {
+                    self.hid.protocol
+                } else {
+                    REPORT_PROTOCOL
+                };
+                self.pma.pma_area.set_u16(EP0_TX, protocol.into());
                 self.pma.pma_area.set_u16(2, 1);
                 self.usb.usb_ep0r.toggle_out();
             }
             (0xa1, UsbRequest::Two) => {
                 // actually set_idle
-                //self.pma.pma_area.set_u16(0x40, 0);
+                //self.pma.pma_area.set_u16(EP0_TX, 0);
                 //self.pma.pma_area.set_u16(2, 2);
                 self.usb.usb_ep0r.toggle_tx_stall();
             }
@@ -1,4 +1,4 @@
-use stm32l1::stm32l151::usb::{USB_EP0R, USB_EP1R};
+use stm32l1::stm32l151::usb::{USB_EP0R, USB_EP1R, USB_EP2R, USB_EP3R};
 
 pub trait UsbEpExt {
     fn toggle_tx_out(&self);
@@ -42,24 +42,32 @@ impl UsbEpExt for USB_EP0R {
     }
 }
 
-impl UsbEpExt for USB_EP1R {
-    fn toggle_tx_stall(&self) {
-        self.toggle(EP_TX_RX_MASK, EP_RX_VALID | EP_TX_STALL, 0)
-    }
+/// Implements `UsbEpExt` for the interrupt IN endpoints, which all
+/// behave the same
+macro_rules! impl_in_ep {
+    ($($ep:ty),*) => {$(
+        impl UsbEpExt for $ep {
+            fn toggle_tx_stall(&self) {
+                self.toggle(EP_TX_RX_MASK, EP_RX_VALID | EP_TX_STALL, 0)
+            }
 
-    fn toggle_tx_out(&self) {
-        self.toggle(EP_TX_MASK, EP_TX_VALID, 0)
-    }
+            fn toggle_tx_out(&self) {
+                self.toggle(EP_TX_MASK, EP_TX_VALID, 0)
+            }
 
-    fn toggle_out(&self) {
-        self.toggle(EP_TX_RX_MASK, EP_TX_RX_VALID, EP_STATUS_OUT)
-    }
+            fn toggle_out(&self) {
+                self.toggle(EP_TX_RX_MASK, EP_TX_RX_VALID, EP_STATUS_OUT)
+            }
 
-    fn toggle_0(&self) {
-        self.toggle(EP_TX_RX_MASK, EP_TX_RX_VALID, 0)
-    }
+            fn toggle_0(&self) {
+                self.toggle(EP_TX_RX_MASK, EP_TX_RX_VALID, 0)
+            }
 
-    fn toggle(&self, mask: u32, val: u32, flags: u32) {
-        self.modify(|r, w| unsafe { w.bits(((r.bits() & (EP_MASK | mask)) ^ val) | flags) })
-    }
+            fn toggle(&self, mask: u32, val: u32, flags: u32) {
+                self.modify(|r, w| unsafe { w.bits(((r.bits() & (EP_MASK | mask)) ^ val) | flags) })
+            }
+        }
+    )*};
 }
+
+impl_in_ep!(USB_EP1R, USB_EP2R, USB_EP3R);